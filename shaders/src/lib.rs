@@ -2,11 +2,16 @@
 #![feature(unchecked_math)]
 use core::mem;
 use core::f32::consts::PI;
-use spirv_std::{spirv, Sampler};
-use spirv_std::image::Image2d;
-use spirv_std::glam::{Vec2, Vec3, Vec4};
+use spirv_std::{spirv, Image, Sampler};
+use spirv_std::image::{Image2d, Image2dArray};
+use spirv_std::glam::{IVec2, UVec2, UVec3, Vec2, Vec3, Vec4};
 use spirv_std::num_traits::Float;
-use shared::{Consts, Material};
+use spirv_std::arch::atomic_i_add;
+use spirv_std::memory::{Scope, Semantics};
+use shared::{luminance, unreal, Aov, Consts, DenoisePass, Instance, Material, Path, Projection};
+use shared::{Sphere, MAX_BOUNCES, NO_TEXTURE, QUEUES, QUEUE_HEADER, QUEUE_WORKGROUP};
+use shared::{ALBEDO_LAYER, DIRECT_LAYER, EMISSION_LAYER, INDIRECT_LAYER, NORMAL_LAYER};
+use shared::{FLAT_SAMPLES, POSITION_LAYER};
 
 #[spirv(vertex)]
 pub fn quad_v(
@@ -22,13 +27,17 @@ pub fn quad_v(
 struct Ray {
   origin: Vec3,
   dir: Vec3,
+  /// Point in the shutter interval, from 0 at the start of the frame to 1 at
+  /// the start of the next.
+  time: f32,
 }
 
 impl Ray {
-  fn new(origin: Vec3, dir: Vec3) -> Self {
+  fn new(origin: Vec3, dir: Vec3, time: f32) -> Self {
     Self {
       origin,
       dir: dir.normalize(),
+      time,
     }
   }
 
@@ -37,15 +46,14 @@ impl Ray {
   }
 }
 
-struct Sphere {
-  pos: Vec3,
-  radius: f32,
-  mat: usize,
+trait Hittable {
+  fn hit(&self, ray: &Ray, min: f32, max: f32) -> Hit;
 }
 
-impl Sphere {
+impl Hittable for Sphere {
   fn hit(&self, ray: &Ray, min: f32, max: f32) -> Hit {
-    let oc = ray.origin - self.pos;
+    let center = self.pos.lerp(self.pos_end, ray.time);
+    let oc = ray.origin - center;
     let a = ray.dir.length_squared();
     let b = oc.dot(ray.dir);
     let c = oc.length_squared() - self.radius * self.radius;
@@ -63,29 +71,27 @@ impl Sphere {
       }
     }
     let pos = ray.at(distance);
-    let normal = (pos - self.pos) / self.radius;
+    let normal = (pos - center) / self.radius;
     let front_face = ray.dir.dot(normal) < 0.0;
+    let uv = to_equirect(normal);
+    let normal = if front_face { normal } else { -normal };
     Hit {
       distance,
       pos,
-      normal: if front_face { normal } else { -normal },
+      normal,
+      tangent: tangent(normal),
+      uv,
       front_face,
-      mat: self.mat,
+      mat: self.mat as usize,
+      sphere: true,
     }
   }
 }
 
-struct Mesh {
-  start: usize,
-  end: usize,
-  aabb: AABB,
-  mat: usize,
-}
-
 #[derive(Copy, Clone, Default)]
 pub struct Tri(Vec3, Vec3, Vec3, usize);
 
-impl Tri {
+impl Hittable for Tri {
   fn hit(&self, ray: &Ray, min: f32, max: f32) -> Hit {
     let ab = self.1 - self.0;
     let ac = self.2 - self.0;
@@ -105,13 +111,18 @@ impl Tri {
     let distance = ac.dot(v_vec) * inv_det;
     let normal = ab.cross(ac).normalize();
     let front_face = ray.dir.dot(normal) < 0.0;
+    let normal = if front_face { normal } else { -normal };
     if distance > min && distance < max {
       Hit {
         distance,
         pos: ray.at(distance),
-        normal: if front_face { normal } else { -normal },
+        normal,
+        tangent: tangent(normal),
+        // barycentric until the mesh maps it to texture coordinates
+        uv: Vec2::new(u, v),
         front_face,
         mat: self.3,
+        sphere: false,
       }
     } else {
       Hit::default()
@@ -138,42 +149,241 @@ struct Hit {
   distance: f32,
   pos: Vec3,
   normal: Vec3,
+  tangent: Vec3,
+  uv: Vec2,
   front_face: bool,
   mat: usize,
+  /// Whether a sphere was hit, which lights are sampled from.
+  sphere: bool,
 }
 
 struct Camera {
   pos: Vec3,
+  forward: Vec3,
+  right: Vec3,
+  up: Vec3,
   coord: Vec2,
   size: Vec2,
   fov: f32,
   defocus: f32,
   focal_length: f32,
+  ortho_size: f32,
+  projection: Projection,
+  blades: u32,
+  blade_rotation: f32,
+  bokeh_size: UVec2,
+  time: f32,
 }
 
 impl Camera {
-  fn new(pos: Vec3, coord: Vec2, size: Vec2) -> Self {
+  /// Camera for the pixel at `coord`, posed where it is at `time` in the
+  /// shutter interval.
+  fn new(consts: &Consts, coord: Vec2, time: f32) -> Self {
+    let yaw = consts.cam_yaw + (consts.cam_yaw_end - consts.cam_yaw) * time;
+    let pitch = consts.cam_pitch + (consts.cam_pitch_end - consts.cam_pitch) * time;
+    let forward = Vec3::new(
+      -yaw.sin() * pitch.cos(),
+      pitch.sin(),
+      -yaw.cos() * pitch.cos(),
+    );
+    let right = Vec3::new(yaw.cos(), 0.0, -yaw.sin());
     Self {
-      pos,
+      pos: consts.cam_pos.lerp(consts.cam_pos_end, time),
+      forward,
+      right,
+      up: right.cross(forward),
       coord,
-      size,
-      fov: 0.6,
-      defocus: 0.05,
-      focal_length: 5.0,
+      size: consts.size,
+      fov: consts.cam_fov,
+      defocus: consts.cam_aperture,
+      focal_length: consts.cam_focus,
+      ortho_size: consts.cam_ortho_size,
+      projection: consts.cam_projection.into(),
+      blades: consts.cam_blades,
+      blade_rotation: consts.cam_blade_rotation,
+      bokeh_size: consts.bokeh_size,
+      time,
+    }
+  }
+
+  /// Ray through `coord` offset by `jitter` pixels, leaving the camera from
+  /// the center of the lens.
+  fn primary(&self, jitter: Vec2) -> Ray {
+    let uv = (self.coord + jitter) / self.size;
+    let relative = (2.0 * uv - Vec2::ONE) * Vec2::new(self.size.x / self.size.y, -1.0);
+    match self.projection {
+      Projection::Perspective => {
+        let relative = relative * self.fov.tan();
+        Ray::new(
+          self.pos,
+          self.forward + relative.x * self.right + relative.y * self.up,
+          self.time,
+        )
+      }
+      Projection::Orthographic => {
+        let offset = relative * self.ortho_size;
+        Ray::new(
+          self.pos + offset.x * self.right + offset.y * self.up,
+          self.forward,
+          self.time,
+        )
+      }
+      Projection::Equirect => {
+        // inverse of `to_equirect`, centered on the view direction
+        let phi = (uv.x - 0.5) * 2.0 * PI;
+        let theta = uv.y * PI;
+        let around = phi.cos() * self.forward + phi.sin() * self.right;
+        Ray::new(
+          self.pos,
+          theta.sin() * around + theta.cos() * self.up,
+          self.time,
+        )
+      }
+      Projection::Fisheye => {
+        // equidistant, `fov` is the angle at the top and bottom edges
+        let theta = (relative.length() * self.fov).min(PI);
+        let side = relative.normalize_or_zero();
+        let side = side.x * self.right + side.y * self.up;
+        Ray::new(
+          self.pos,
+          theta.cos() * self.forward + theta.sin() * side,
+          self.time,
+        )
+      }
+    }
+  }
+
+  /// Point on the lens, from the aperture mask if there is one.
+  fn lens(&self, bokeh: &[f32], rng: &mut Rng) -> Vec2 {
+    if self.bokeh_size.x > 0 {
+      sample_mask(bokeh, self.bokeh_size, rng)
+    } else if self.blades >= 3 {
+      rng.gen_in_polygon(self.blades, self.blade_rotation)
+    } else {
+      rng.gen_in_circle()
     }
   }
 
-  fn ray(&mut self, rng: &mut Rng) -> Ray {
-    let relative =
-      Vec2::new(self.coord.x + rng.gen(), self.coord.y + rng.gen()) * 2.0 / self.size - Vec2::ONE;
-    let dir = -(relative * Vec2::new(self.size.x / self.size.y, 1.0) * self.fov.tan()).extend(1.0);
-    let start = self.pos + (self.defocus * rng.gen_in_circle()).extend(0.0);
-    let target = self.pos + dir * self.focal_length;
-    Ray::new(start, target - start)
+  fn ray(&mut self, bokeh: &[f32], rng: &mut Rng) -> Ray {
+    let primary = self.primary(Vec2::new(rng.gen(), rng.gen()));
+    if !matches!(self.projection, Projection::Perspective) {
+      return primary;
+    }
+    let lens = self.defocus * self.lens(bokeh, rng);
+    let start = self.pos + lens.x * self.right + lens.y * self.up;
+    let target = self.pos + primary.dir * self.focal_length / primary.dir.dot(self.forward);
+    Ray::new(start, target - start, self.time)
   }
 }
 
-const MAX_BOUNCES: usize = 32;
+/// The accumulated samples, summed rather than averaged, with the number of
+/// samples in alpha.
+type Sums = Image!(2D, format = rgba32f, sampled = false);
+/// Sums of the squared luminance of the samples, for their variance.
+type Moments = Image!(2D, format = r32f, sampled = false);
+/// The AOVs, one layer each, see `ALBEDO_LAYER` and the rest.
+type Aovs = Image!(2D, format = rgba32f, sampled = false, arrayed = true);
+/// Output of a pass of the denoiser, or of `aov_show`.
+type Filtered = Image!(2D, format = rgba32f, sampled = false);
+
+/// Distance given to camera rays that hit the sky, to keep the denoiser from
+/// blending it with the geometry.
+const SKY_DEPTH: f32 = 1e6;
+
+/// One pixel of each layer of the AOVs.
+struct Passes {
+  albedo: Vec4,
+  normal: Vec4,
+  position: Vec4,
+  direct: Vec4,
+  indirect: Vec4,
+  emission: Vec4,
+}
+
+impl Passes {
+  const ZERO: Self = Self {
+    albedo: Vec4::ZERO,
+    normal: Vec4::ZERO,
+    position: Vec4::ZERO,
+    direct: Vec4::ZERO,
+    indirect: Vec4::ZERO,
+    emission: Vec4::ZERO,
+  };
+
+  fn read(aovs: &Aovs, px: UVec2) -> Self {
+    Self {
+      albedo: aovs.read(px.extend(ALBEDO_LAYER)),
+      normal: aovs.read(px.extend(NORMAL_LAYER)),
+      position: aovs.read(px.extend(POSITION_LAYER)),
+      direct: aovs.read(px.extend(DIRECT_LAYER)),
+      indirect: aovs.read(px.extend(INDIRECT_LAYER)),
+      emission: aovs.read(px.extend(EMISSION_LAYER)),
+    }
+  }
+
+  fn write(&self, aovs: &Aovs, px: UVec2) {
+    unsafe {
+      aovs.write(px.extend(ALBEDO_LAYER), self.albedo);
+      aovs.write(px.extend(NORMAL_LAYER), self.normal);
+      aovs.write(px.extend(POSITION_LAYER), self.position);
+      aovs.write(px.extend(DIRECT_LAYER), self.direct);
+      aovs.write(px.extend(INDIRECT_LAYER), self.indirect);
+      aovs.write(px.extend(EMISSION_LAYER), self.emission);
+    }
+  }
+
+  /// Adds the first `hit` of a camera ray. The material is only taken from
+  /// the `first` sample of the pixel, as an average of them means nothing.
+  fn add_hit(&mut self, hit: &Hit, materials: &[Material], first: bool) {
+    let (albedo, normal, pos, id) = if hit.distance == f32::MAX {
+      (Vec3::ONE, Vec3::ZERO.extend(SKY_DEPTH), Vec3::ZERO, 0.0)
+    } else {
+      let albedo = materials[hit.mat].base_color;
+      (
+        albedo,
+        hit.normal.extend(hit.distance),
+        hit.pos,
+        hit.mat as f32 + 1.0,
+      )
+    };
+    self.albedo += albedo.extend(1.0);
+    self.normal += normal;
+    let id = if first { id } else { self.position.w };
+    self.position = (self.position.truncate() + pos).extend(id);
+  }
+
+  /// Adds `light` reaching the camera after `bounces` bounces.
+  fn add_light(&mut self, light: Vec3, bounces: u32) {
+    match bounces {
+      0 => self.emission += light.extend(0.0),
+      1 => self.direct += light.extend(0.0),
+      _ => self.indirect += light.extend(0.0),
+    }
+  }
+}
+
+/// Samples a pixel gets before its variance is trusted to stop sampling it.
+const MIN_SAMPLES: f32 = 16.0;
+
+/// Whether the pixel at `px` is known well enough to skip, given the noise
+/// threshold in `consts`.
+fn converged(px: UVec2, consts: &Consts, sums: &Sums, moments: &Moments) -> bool {
+  if consts.noise <= 0.0 || consts.samples == 1 {
+    return false;
+  }
+  let sum = sums.read(px);
+  let n = sum.w;
+  if n < MIN_SAMPLES {
+    return false;
+  }
+  let mean = luminance(sum.truncate()) / n;
+  let variance = (moments.read(px).x / n - mean * mean).max(0.0);
+  if variance <= 0.0 {
+    return n >= FLAT_SAMPLES;
+  }
+  // standard error of the mean, relative to the mean
+  (variance / (n - 1.0)).sqrt() <= consts.noise * mean.max(0.01)
+}
 
 fn hash(key: u32) -> u32 {
   let mut h = 0;
@@ -188,77 +398,515 @@ fn hash(key: u32) -> u32 {
   h
 }
 
-#[spirv(fragment)]
-pub fn main_f(
-  uv: Vec2,
-  #[spirv(frag_coord)] frag_coord: Vec4,
+/// Adds `consts.batch` samples to the pixel of the tile at `id`, accumulating
+/// in place.
+#[spirv(compute(threads(8, 8)))]
+pub fn main_c(
+  #[spirv(global_invocation_id)] id: UVec3,
   #[spirv(uniform, descriptor_set = 0, binding = 0)] consts: &Consts,
   #[spirv(descriptor_set = 1, binding = 0)] sampler: &Sampler,
-  #[spirv(descriptor_set = 2, binding = 0)] prev: &Image2d,
+  #[spirv(descriptor_set = 2, binding = 0)] sums: &Sums,
+  #[spirv(descriptor_set = 2, binding = 1)] moments: &Moments,
+  #[spirv(descriptor_set = 2, binding = 2)] aovs: &Aovs,
   #[spirv(descriptor_set = 3, binding = 0)] sky: &Image2d,
   #[spirv(storage_buffer, descriptor_set = 4, binding = 0)] vtx_buf: &mut [Vec4],
-  #[spirv(storage_buffer, descriptor_set = 4, binding = 1)] materials: &mut [Vec4],
-  out_color: &mut Vec4,
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 1)] materials: &mut [Material],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 2)] uv_buf: &mut [Vec2],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 3)] spheres: &mut [Sphere],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 4)] instances: &mut [Instance],
+  #[spirv(descriptor_set = 5, binding = 0)] masks: &Image2dArray,
+  #[spirv(storage_buffer, descriptor_set = 6, binding = 0)] bokeh: &mut [f32],
 ) {
-  let coord = Vec2::new(frag_coord.x, frag_coord.y);
+  let px = id.truncate();
+  // the workgroups overhang the tile at its right and bottom edges
+  if px.as_vec2().cmpge(consts.tile_size).any() || converged(px, consts, sums, moments) {
+    return;
+  }
+  let coord = px.as_vec2() + 0.5 + consts.tile_offset;
   let mut rng = Rng(consts.rand ^ hash((coord.x + consts.size.y * coord.y) as _));
-  let mut cam = Camera::new(Vec3::new(0.0, 1.5, 0.0), coord, consts.size);
-  let spheres = [
-    Sphere {
-      pos: Vec3::new(0.0, -200.0, 0.0),
-      radius: 200.0,
-      mat: 0,
-    },
-    Sphere {
-      pos: Vec3::new(-3.0, 1.5, -7.5),
-      radius: 1.5,
-      mat: 1,
-    },
-    Sphere {
-      pos: Vec3::new(0.0, 1.5, -10.0),
-      radius: 1.5,
-      mat: 2,
-    },
-    Sphere {
-      pos: Vec3::new(3.0, 1.5, -7.5),
-      radius: 1.5,
-      mat: 3,
-    },
-    Sphere {
-      pos: Vec3::new(0.0, 1.5, 2.5),
-      radius: 1.5,
-      mat: 4,
-    },
-    Sphere {
-      pos: Vec3::new(1.5, 1.0, -3.0),
-      radius: 0.75,
-      mat: 5,
-    },
-    Sphere {
-      pos: Vec3::new(-1.5, 1.0, -3.0),
-      radius: 0.75,
-      mat: 6,
-    },
-    // Sphere {
-    //   pos: Vec3::new(6.0, 6.0, 6.0),
-    //   radius: 4.0,
-    //   mat: 7,
-    // },
-  ];
-  let meshes = [Mesh {
-    start: 0,
-    end: 2901,
-    aabb: AABB(
-      Vec3::new(-1.040056, 0.026624, -6.060498),
-      Vec3::new(1.442725, 1.795877, -4.065464),
-    ),
-    mat: 0,
-  }];
-
-  *out_color = prev.sample_by_lod(*sampler, Vec2::new(uv.x, 1.0 - uv.y), 1.0);
+  let (mut out_color, mut moment) = if consts.samples > 1 {
+    (sums.read(px), moments.read(px).x)
+  } else {
+    (Vec4::ZERO, 0.0)
+  };
+  let mut passes = if consts.samples > 1 {
+    Passes::read(aovs, px)
+  } else {
+    Passes::ZERO
+  };
+
+  for _ in 0..consts.batch {
+    let time = consts.shutter_open + (consts.shutter_close - consts.shutter_open) * rng.gen_pos();
+    let mut cam = Camera::new(consts, coord, time);
+    let mut sample = Vec3::ZERO;
+
+    let wavelength = rng.gen_pos() * 370.0 + 380.0;
+    let mut attenuation = spectrum(wavelength);
+    let mut ray = cam.ray(bokeh, &mut rng);
+    let mut nee = false;
+    for bounce in 0..MAX_BOUNCES {
+      let closest = trace(
+        &ray, spheres, instances, vtx_buf, uv_buf, materials, sampler, masks, &mut rng,
+      );
+      if bounce == 0 {
+        passes.add_hit(&closest, materials, out_color.w == 0.0);
+      }
+
+      if closest.distance != f32::MAX {
+        let mat = &materials[closest.mat];
+        // lights sampled at the last hit already added their light
+        if !(nee && closest.sphere) {
+          let light = mat.emission * mat.emission_strength * attenuation;
+          sample += light;
+          passes.add_light(light, bounce);
+        }
+        let lobe = pick_lobe(mat, &ray, &closest, &mut rng);
+        nee = false;
+        if lobe == Lobe::Diffuse {
+          let light = sample_light(closest.pos, ray.time, spheres, materials, &mut rng);
+          nee = light.light.cmpgt(Vec3::ZERO).any();
+          let shadow = Ray::new(closest.pos, light.dir, ray.time);
+          if nee
+            && unoccluded(
+              &shadow,
+              light.distance,
+              spheres,
+              instances,
+              vtx_buf,
+              uv_buf,
+              materials,
+              sampler,
+              masks,
+              &mut rng,
+            )
+          {
+            let light = attenuation * direct(mat, &ray, &closest, &light);
+            sample += light;
+            passes.add_light(light, bounce + 1);
+          }
+        }
+        let (dir, color) = sample_lobe(lobe, mat, &ray, &closest, wavelength, &mut rng);
+        ray = Ray::new(closest.pos, dir, ray.time);
+        attenuation *= color;
+        if attenuation.cmple(Vec3::ZERO).all() {
+          break;
+        }
+      } else {
+        let light = sky
+          .sample_by_lod(*sampler, to_equirect(ray.dir), 1.0)
+          .truncate()
+          * attenuation;
+        sample += light;
+        passes.add_light(light, bounce);
+        break;
+      }
+    }
+    // alpha counts the samples in each pixel
+    out_color += sample.extend(1.0);
+    moment += luminance(sample) * luminance(sample);
+  }
+  unsafe {
+    sums.write(px, out_color);
+    moments.write(px, Vec4::splat(moment));
+  }
+  passes.write(aovs, px);
+}
+
+// queues of the wavefront integrator, see `QUEUES`
+const RAYS: usize = 0;
+const GLOSSY: usize = 1;
+const TRANSMISSION: usize = 2;
+const DIFFUSE: usize = 3;
+const SHADOWS: usize = 4;
+// counters in each queue header besides the indirect dispatch arguments
+const PUSHED: usize = 0;
+const ITEMS: usize = 4;
+
+/// Appends the path `i` to `queue`. `capacity` is the number of entries each
+/// queue has room for, one per pixel of the tile.
+fn push(queues: &mut [u32], queue: usize, capacity: usize, i: u32) {
+  let slot = unsafe {
+    atomic_i_add::<u32, { Scope::Device as u32 }, { Semantics::NONE.bits() }>(
+      &mut queues[queue * QUEUE_HEADER + PUSHED],
+      1,
+    )
+  };
+  queues[QUEUES * QUEUE_HEADER + queue * capacity + slot as usize] = i;
+}
+
+/// The path the invocation `id` of a kernel consuming `queue` works on, if
+/// there is one for it.
+fn pop(queues: &[u32], queue: usize, capacity: usize, id: UVec3) -> Option<usize> {
+  if id.x < queues[queue * QUEUE_HEADER + ITEMS] {
+    Some(queues[QUEUES * QUEUE_HEADER + queue * capacity + id.x as usize] as usize)
+  } else {
+    None
+  }
+}
+
+/// Entries each queue has room for.
+fn capacity(consts: &Consts) -> usize {
+  (consts.tile_size.x * consts.tile_size.y) as usize
+}
+
+/// Pixel of the tile `path` is for.
+fn pixel(path: &Path, consts: &Consts) -> UVec2 {
+  let width = consts.tile_size.x as u32;
+  UVec2::new(path.pixel % width, path.pixel / width)
+}
+
+/// Adds the finished `path` to its pixel.
+fn finish(path: &Path, consts: &Consts, sums: &Sums, moments: &Moments) {
+  let px = pixel(path, consts);
+  // alpha counts the samples in each pixel
+  let sum = sums.read(px) + path.color.extend(1.0);
+  let moment = moments.read(px).x + luminance(path.color) * luminance(path.color);
+  unsafe {
+    sums.write(px, sum);
+    moments.write(px, Vec4::splat(moment));
+  }
+}
+
+/// Clears the accumulated samples of the tile, before the first sample of
+/// the wavefront integrator.
+#[spirv(compute(threads(8, 8)))]
+pub fn wf_clear(
+  #[spirv(global_invocation_id)] id: UVec3,
+  #[spirv(uniform, descriptor_set = 0, binding = 0)] consts: &Consts,
+  #[spirv(descriptor_set = 2, binding = 0)] sums: &Sums,
+  #[spirv(descriptor_set = 2, binding = 1)] moments: &Moments,
+  #[spirv(descriptor_set = 2, binding = 2)] aovs: &Aovs,
+) {
+  let px = id.truncate();
+  if px.as_vec2().cmplt(consts.tile_size).all() {
+    unsafe {
+      sums.write(px, Vec4::ZERO);
+      moments.write(px, Vec4::ZERO);
+    }
+    Passes::ZERO.write(aovs, px);
+  }
+}
+
+/// Starts one path per pixel of the tile, like `main_c` does, and queues its
+/// camera ray. The first kernel of each sample of the wavefront integrator.
+#[spirv(compute(threads(8, 8)))]
+pub fn wf_generate(
+  #[spirv(global_invocation_id)] id: UVec3,
+  #[spirv(uniform, descriptor_set = 0, binding = 0)] consts: &Consts,
+  #[spirv(descriptor_set = 2, binding = 0)] sums: &Sums,
+  #[spirv(descriptor_set = 2, binding = 1)] moments: &Moments,
+  #[spirv(storage_buffer, descriptor_set = 6, binding = 0)] bokeh: &mut [f32],
+  #[spirv(storage_buffer, descriptor_set = 7, binding = 0)] paths: &mut [Path],
+  #[spirv(storage_buffer, descriptor_set = 7, binding = 1)] queues: &mut [u32],
+) {
+  let px = id.truncate();
+  if px.as_vec2().cmpge(consts.tile_size).any() || converged(px, consts, sums, moments) {
+    return;
+  }
+  let coord = px.as_vec2() + 0.5 + consts.tile_offset;
+  let i = px.y * consts.tile_size.x as u32 + px.x;
+  let path = &mut paths[i as usize];
+  // carries on from the pixel's last path, so the samples of a batch differ
+  let seed = hash((coord.x + consts.size.y * coord.y) as u32 ^ path.rng);
+  let mut rng = Rng(consts.rand ^ seed);
+  let time = consts.shutter_open + (consts.shutter_close - consts.shutter_open) * rng.gen_pos();
+  let mut cam = Camera::new(consts, coord, time);
 
   let wavelength = rng.gen_pos() * 370.0 + 380.0;
-  let mut attenuation = match wavelength {
+  let ray = cam.ray(bokeh, &mut rng);
+  path.origin = ray.origin;
+  path.time = ray.time;
+  path.dir = ray.dir;
+  path.wavelength = wavelength;
+  path.attenuation = spectrum(wavelength);
+  path.rng = rng.0;
+  path.color = Vec3::ZERO;
+  path.pixel = i;
+  path.bounces = 0;
+  path.nee = 0;
+  push(queues, RAYS, capacity(consts), i);
+}
+
+/// Hands what was pushed to `queue` over to the kernel consuming it, as the
+/// number of entries and the workgroups to dispatch for them, and empties the
+/// queue for the next pushes.
+fn hand_over(queues: &mut [u32], queue: usize) {
+  let header = queue * QUEUE_HEADER;
+  let pushed = queues[header + PUSHED];
+  queues[header + 1] = (pushed + QUEUE_WORKGROUP - 1) / QUEUE_WORKGROUP;
+  queues[header + 2] = 1;
+  queues[header + 3] = 1;
+  queues[header + ITEMS] = pushed;
+  queues[header + PUSHED] = 0;
+}
+
+/// Hands every queue over to its kernel. Runs between the other kernels.
+#[spirv(compute(threads(1)))]
+pub fn wf_queues(#[spirv(storage_buffer, descriptor_set = 7, binding = 1)] queues: &mut [u32]) {
+  for q in 0..QUEUES {
+    hand_over(queues, q);
+  }
+}
+
+/// Hands just the shadow rays over to `wf_shadow`, leaving the rays queued by
+/// the shading kernels to pile up with the ones `wf_shadow` queues.
+#[spirv(compute(threads(1)))]
+pub fn wf_shadow_queue(
+  #[spirv(storage_buffer, descriptor_set = 7, binding = 1)] queues: &mut [u32],
+) {
+  hand_over(queues, SHADOWS);
+}
+
+/// Traces the queued rays. Misses pick up the sky and finish, hits pick up
+/// emission, pick a lobe and move on to the queue of its shading kernel.
+#[spirv(compute(threads(64)))]
+pub fn wf_extend(
+  #[spirv(global_invocation_id)] id: UVec3,
+  #[spirv(uniform, descriptor_set = 0, binding = 0)] consts: &Consts,
+  #[spirv(descriptor_set = 1, binding = 0)] sampler: &Sampler,
+  #[spirv(descriptor_set = 2, binding = 0)] sums: &Sums,
+  #[spirv(descriptor_set = 2, binding = 1)] moments: &Moments,
+  #[spirv(descriptor_set = 2, binding = 2)] aovs: &Aovs,
+  #[spirv(descriptor_set = 3, binding = 0)] sky: &Image2d,
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 0)] vtx_buf: &mut [Vec4],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 1)] materials: &mut [Material],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 2)] uv_buf: &mut [Vec2],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 3)] spheres: &mut [Sphere],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 4)] instances: &mut [Instance],
+  #[spirv(descriptor_set = 5, binding = 0)] masks: &Image2dArray,
+  #[spirv(storage_buffer, descriptor_set = 7, binding = 0)] paths: &mut [Path],
+  #[spirv(storage_buffer, descriptor_set = 7, binding = 1)] queues: &mut [u32],
+) {
+  let capacity = capacity(consts);
+  let Some(i) = pop(queues, RAYS, capacity, id) else {
+    return;
+  };
+  let path = &mut paths[i];
+  let mut rng = Rng(path.rng);
+  let ray = Ray::new(path.origin, path.dir, path.time);
+  let hit = trace(
+    &ray, spheres, instances, vtx_buf, uv_buf, materials, sampler, masks, &mut rng,
+  );
+  // each pixel has one path in flight, so this is the only thread on it
+  let px = pixel(path, consts);
+  let mut passes = Passes::read(aovs, px);
+  if path.bounces == 0 {
+    // the sample count only goes up once the path finishes
+    passes.add_hit(&hit, materials, sums.read(px).w == 0.0);
+  }
+  if hit.distance == f32::MAX {
+    let light = sky
+      .sample_by_lod(*sampler, to_equirect(ray.dir), 1.0)
+      .truncate()
+      * path.attenuation;
+    path.color += light;
+    passes.add_light(light, path.bounces);
+    passes.write(aovs, px);
+    finish(path, consts, sums, moments);
+    return;
+  }
+  let mat = &materials[hit.mat];
+  // lights sampled at the last hit already added their light
+  if path.nee == 0 || !hit.sphere {
+    let light = mat.emission * mat.emission_strength * path.attenuation;
+    path.color += light;
+    passes.add_light(light, path.bounces);
+  }
+  passes.write(aovs, px);
+  let lobe = pick_lobe(mat, &ray, &hit, &mut rng);
+  path.origin = hit.pos;
+  path.dir = ray.dir;
+  path.normal = hit.normal;
+  path.tangent = hit.tangent;
+  path.front_face = hit.front_face as u32;
+  path.mat = hit.mat as u32;
+  path.lobe = lobe as u32;
+  path.rng = rng.0;
+  push(queues, lobe.queue(), capacity, i as u32);
+}
+
+/// Samples the lobe picked for each path in `queue`. Diffuse hits also sample
+/// a light and queue the shadow ray towards it, the rest move on right away.
+fn shade(
+  queue: usize,
+  id: UVec3,
+  consts: &Consts,
+  sums: &Sums,
+  moments: &Moments,
+  materials: &[Material],
+  spheres: &[Sphere],
+  paths: &mut [Path],
+  queues: &mut [u32],
+) {
+  let capacity = capacity(consts);
+  let Some(i) = pop(queues, queue, capacity, id) else {
+    return;
+  };
+  let path = &mut paths[i];
+  let mut rng = Rng(path.rng);
+  let ray = Ray {
+    origin: path.origin,
+    dir: path.dir,
+    time: path.time,
+  };
+  let hit = Hit {
+    distance: 0.0,
+    pos: path.origin,
+    normal: path.normal,
+    tangent: path.tangent,
+    uv: Vec2::ZERO,
+    front_face: path.front_face != 0,
+    mat: path.mat as usize,
+    sphere: false,
+  };
+  let mat = &materials[hit.mat];
+  path.nee = 0;
+  if queue == DIFFUSE {
+    let light = sample_light(hit.pos, ray.time, spheres, materials, &mut rng);
+    if light.light.cmpgt(Vec3::ZERO).any() {
+      path.nee = 1;
+      path.shadow_dir = light.dir;
+      path.shadow_distance = light.distance;
+      path.shadow_light = path.attenuation * direct(mat, &ray, &hit, &light);
+    }
+  }
+  let (dir, color) = sample_lobe(path.lobe.into(), mat, &ray, &hit, path.wavelength, &mut rng);
+  path.dir = dir;
+  path.attenuation *= color;
+  path.bounces += 1;
+  path.rng = rng.0;
+  if path.nee != 0 {
+    push(queues, SHADOWS, capacity, i as u32);
+  } else {
+    bounce(path, i, consts, sums, moments, queues);
+  }
+}
+
+/// Queues the bounced ray of the shaded `path`, or finishes it once it
+/// carries no more light or runs out of bounces.
+fn bounce(
+  path: &Path,
+  i: usize,
+  consts: &Consts,
+  sums: &Sums,
+  moments: &Moments,
+  queues: &mut [u32],
+) {
+  if path.bounces == MAX_BOUNCES || path.attenuation.cmple(Vec3::ZERO).all() {
+    finish(path, consts, sums, moments);
+  } else {
+    push(queues, RAYS, capacity(consts), i as u32);
+  }
+}
+
+#[spirv(compute(threads(64)))]
+pub fn wf_shade_glossy(
+  #[spirv(global_invocation_id)] id: UVec3,
+  #[spirv(uniform, descriptor_set = 0, binding = 0)] consts: &Consts,
+  #[spirv(descriptor_set = 2, binding = 0)] sums: &Sums,
+  #[spirv(descriptor_set = 2, binding = 1)] moments: &Moments,
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 1)] materials: &mut [Material],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 3)] spheres: &mut [Sphere],
+  #[spirv(storage_buffer, descriptor_set = 7, binding = 0)] paths: &mut [Path],
+  #[spirv(storage_buffer, descriptor_set = 7, binding = 1)] queues: &mut [u32],
+) {
+  shade(
+    GLOSSY, id, consts, sums, moments, materials, spheres, paths, queues,
+  );
+}
+
+#[spirv(compute(threads(64)))]
+pub fn wf_shade_transmission(
+  #[spirv(global_invocation_id)] id: UVec3,
+  #[spirv(uniform, descriptor_set = 0, binding = 0)] consts: &Consts,
+  #[spirv(descriptor_set = 2, binding = 0)] sums: &Sums,
+  #[spirv(descriptor_set = 2, binding = 1)] moments: &Moments,
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 1)] materials: &mut [Material],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 3)] spheres: &mut [Sphere],
+  #[spirv(storage_buffer, descriptor_set = 7, binding = 0)] paths: &mut [Path],
+  #[spirv(storage_buffer, descriptor_set = 7, binding = 1)] queues: &mut [u32],
+) {
+  shade(
+    TRANSMISSION,
+    id,
+    consts,
+    sums,
+    moments,
+    materials,
+    spheres,
+    paths,
+    queues,
+  );
+}
+
+#[spirv(compute(threads(64)))]
+pub fn wf_shade_diffuse(
+  #[spirv(global_invocation_id)] id: UVec3,
+  #[spirv(uniform, descriptor_set = 0, binding = 0)] consts: &Consts,
+  #[spirv(descriptor_set = 2, binding = 0)] sums: &Sums,
+  #[spirv(descriptor_set = 2, binding = 1)] moments: &Moments,
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 1)] materials: &mut [Material],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 3)] spheres: &mut [Sphere],
+  #[spirv(storage_buffer, descriptor_set = 7, binding = 0)] paths: &mut [Path],
+  #[spirv(storage_buffer, descriptor_set = 7, binding = 1)] queues: &mut [u32],
+) {
+  shade(
+    DIFFUSE, id, consts, sums, moments, materials, spheres, paths, queues,
+  );
+}
+
+/// Traces the shadow rays queued by the diffuse shading kernel, adds the
+/// light of those that get through and moves their paths on.
+#[spirv(compute(threads(64)))]
+pub fn wf_shadow(
+  #[spirv(global_invocation_id)] id: UVec3,
+  #[spirv(uniform, descriptor_set = 0, binding = 0)] consts: &Consts,
+  #[spirv(descriptor_set = 1, binding = 0)] sampler: &Sampler,
+  #[spirv(descriptor_set = 2, binding = 0)] sums: &Sums,
+  #[spirv(descriptor_set = 2, binding = 1)] moments: &Moments,
+  #[spirv(descriptor_set = 2, binding = 2)] aovs: &Aovs,
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 0)] vtx_buf: &mut [Vec4],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 1)] materials: &mut [Material],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 2)] uv_buf: &mut [Vec2],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 3)] spheres: &mut [Sphere],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 4)] instances: &mut [Instance],
+  #[spirv(descriptor_set = 5, binding = 0)] masks: &Image2dArray,
+  #[spirv(storage_buffer, descriptor_set = 7, binding = 0)] paths: &mut [Path],
+  #[spirv(storage_buffer, descriptor_set = 7, binding = 1)] queues: &mut [u32],
+) {
+  let Some(i) = pop(queues, SHADOWS, capacity(consts), id) else {
+    return;
+  };
+  let path = &mut paths[i];
+  let mut rng = Rng(path.rng);
+  let shadow = Ray::new(path.origin, path.shadow_dir, path.time);
+  if unoccluded(
+    &shadow,
+    path.shadow_distance,
+    spheres,
+    instances,
+    vtx_buf,
+    uv_buf,
+    materials,
+    sampler,
+    masks,
+    &mut rng,
+  ) {
+    path.color += path.shadow_light;
+    // each pixel has one path in flight, so this is the only thread on it
+    let px = pixel(path, consts);
+    let mut passes = Passes::read(aovs, px);
+    passes.add_light(path.shadow_light, path.bounces);
+    passes.write(aovs, px);
+  }
+  path.rng = rng.0;
+  bounce(path, i, consts, sums, moments, queues);
+}
+
+/// RGB throughput of a path carrying light of a single `wavelength`, in
+/// nanometers.
+fn spectrum(wavelength: f32) -> Vec3 {
+  let rgb = match wavelength {
     380.0..=440.0 => {
       let at = 0.3 + 0.7 * (wavelength - 380.0) / (440.0 - 380.0);
       Vec3::new((-(wavelength - 440.0) / (440.0 - 380.0)) * at, 0.0, at)
@@ -272,90 +920,605 @@ pub fn main_f(
     }
     _ => Vec3::ZERO,
   };
-  attenuation *= Vec3::new(2.74738275, 2.97417918, 3.33566826); //?
-
-  let mut ray = cam.ray(&mut rng);
-  for _ in 0..MAX_BOUNCES {
-    let mut closest = Hit::default();
-    closest.distance = f32::MAX;
-    for i in 0..spheres.len() {
-      let hit = spheres[i].hit(&ray, 0.001, closest.distance);
-      if hit.distance > 0.0 {
-        closest = hit;
+  rgb * Vec3::new(2.74738275, 2.97417918, 3.33566826) //?
+}
+
+/// Lobes of the principled BSDF, of which each bounce samples one.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Lobe {
+  Clearcoat,
+  Metal,
+  Transmission,
+  Specular,
+  Diffuse,
+}
+
+impl From<u32> for Lobe {
+  fn from(v: u32) -> Self {
+    match v {
+      0 => Self::Clearcoat,
+      1 => Self::Metal,
+      2 => Self::Transmission,
+      3 => Self::Specular,
+      _ => Self::Diffuse,
+    }
+  }
+}
+
+impl Lobe {
+  /// Wavefront queue of the kernel that shades this lobe.
+  fn queue(self) -> usize {
+    match self {
+      Self::Clearcoat | Self::Metal | Self::Specular => GLOSSY,
+      Self::Transmission => TRANSMISSION,
+      Self::Diffuse => DIFFUSE,
+    }
+  }
+}
+
+/// Picks the lobe to sample, each with the probability of its share of the
+/// BSDF.
+fn pick_lobe(mat: &Material, ray: &Ray, hit: &Hit, rng: &mut Rng) -> Lobe {
+  let cos_theta = (-ray.dir).dot(hit.normal).min(1.0);
+  if rng.gen_pos() < mat.clearcoat * schlick(cos_theta, 1.5) {
+    Lobe::Clearcoat
+  } else if rng.gen_pos() < mat.metallic {
+    Lobe::Metal
+  } else if rng.gen_pos() < mat.transmission {
+    Lobe::Transmission
+  } else if rng.gen_pos() < 2.0 * mat.specular * schlick(cos_theta, 1.5) {
+    // specular 0.5 is the usual F0 of 0.04
+    Lobe::Specular
+  } else {
+    Lobe::Diffuse
+  }
+}
+
+/// Samples a direction from `lobe` alone. Returns the scattered direction and
+/// the attenuation.
+fn sample_lobe(
+  lobe: Lobe,
+  mat: &Material,
+  ray: &Ray,
+  hit: &Hit,
+  wavelength: f32,
+  rng: &mut Rng,
+) -> (Vec3, Vec3) {
+  let cos_theta = (-ray.dir).dot(hit.normal).min(1.0);
+  let alpha = mat.roughness * mat.roughness;
+  // same mapping as Blender: anisotropy stretches alpha along the tangent
+  let aspect = (1.0 - 0.9 * mat.anisotropic).sqrt();
+  let alpha = Vec2::new(alpha / aspect, alpha * aspect);
+  let (sin, cos) = (2.0 * PI * mat.anisotropic_rotation).sin_cos();
+  let tangent = cos * hit.tangent + sin * hit.normal.cross(hit.tangent);
+
+  match lobe {
+    Lobe::Clearcoat => {
+      let alpha = Vec2::splat(mat.clearcoat_roughness * mat.clearcoat_roughness);
+      let (dir, weight) = ggx(ray.dir, hit.normal, tangent, alpha, rng);
+      (dir, Vec3::splat(weight))
+    }
+    Lobe::Metal => {
+      let (dir, weight) = ggx(ray.dir, hit.normal, tangent, alpha, rng);
+      (dir, weight * mat.base_color)
+    }
+    Lobe::Transmission => {
+      let ir = mat.ior + (wavelength - 150.0) * 0.0005;
+      let ir = if hit.front_face { 1.0 / ir } else { ir };
+      let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+
+      let cannot_refract = ir * sin_theta > 1.0;
+      let will_reflect = rng.gen_pos() < schlick(cos_theta, ir);
+      if cannot_refract || will_reflect {
+        let (dir, weight) = ggx(ray.dir, hit.normal, tangent, alpha, rng);
+        return (dir, weight * mat.base_color);
       }
+      let dir = refract(ray.dir, hit.normal, ir) + alpha.x * rng.gen_in_sphere();
+      (dir, mat.base_color)
     }
-    for i in 0..meshes.len() {
-      if meshes[i].aabb.hit(&ray) {
-        for f in meshes[i].start..meshes[i].end / 3 {
-          let hit = Tri(
-            vtx_buf[3 * f].truncate(),
-            vtx_buf[3 * f + 1].truncate(),
-            vtx_buf[3 * f + 2].truncate(),
-            meshes[i].mat,
-          )
-          .hit(&ray, 0.001, closest.distance);
-          if hit.distance > 0.0 {
-            closest = hit;
-          }
-        }
+    Lobe::Specular => {
+      let (dir, weight) = ggx(ray.dir, hit.normal, tangent, alpha, rng);
+      (dir, Vec3::splat(weight))
+    }
+    Lobe::Diffuse => (hit.normal + rng.gen_in_sphere(), diffuse(mat, cos_theta)),
+  }
+}
+
+/// Reflectance of the diffuse lobe with sheen, at `cos_theta` to the viewer.
+/// The weight of a cosine-weighted sample of it.
+fn diffuse(mat: &Material, cos_theta: f32) -> Vec3 {
+  let luminance = mat.base_color.dot(Vec3::new(0.3, 0.6, 0.1));
+  let tint = if luminance > 0.0 {
+    mat.base_color / luminance
+  } else {
+    Vec3::ONE
+  };
+  let sheen = mat.sheen * (1.0 - cos_theta).powf(5.0) * Vec3::ONE.lerp(tint, mat.sheen_tint);
+  mat.base_color + sheen
+}
+
+/// A direction towards a light, for next event estimation.
+struct LightSample {
+  dir: Vec3,
+  /// Distance to the light along `dir`.
+  distance: f32,
+  /// Light emitted along `dir` over the probability of sampling it, zero if
+  /// there was no light to sample.
+  light: Vec3,
+}
+
+fn emits(mat: &Material) -> bool {
+  mat.emission_strength > 0.0 && mat.emission.cmpgt(Vec3::ZERO).any()
+}
+
+/// Picks one of the emissive spheres at random, and a direction towards it
+/// uniformly within the cone it covers as seen from `pos` at `time`.
+fn sample_light(
+  pos: Vec3,
+  time: f32,
+  spheres: &[Sphere],
+  materials: &[Material],
+  rng: &mut Rng,
+) -> LightSample {
+  let none = LightSample {
+    dir: Vec3::Z,
+    distance: 0.0,
+    light: Vec3::ZERO,
+  };
+  let mut lights = 0;
+  for i in 0..spheres.len() {
+    if emits(&materials[spheres[i].mat as usize]) {
+      lights += 1;
+    }
+  }
+  if lights == 0 {
+    return none;
+  }
+  let mut pick = ((rng.gen_pos() * lights as f32) as u32).min(lights - 1);
+  let mut light = 0;
+  for i in 0..spheres.len() {
+    if emits(&materials[spheres[i].mat as usize]) {
+      if pick == 0 {
+        light = i;
+        break;
       }
+      pick -= 1;
     }
+  }
+  let sphere = &spheres[light];
+  let to_center = sphere.pos.lerp(sphere.pos_end, time) - pos;
+  let dist_sq = to_center.length_squared();
+  let radius_sq = sphere.radius * sphere.radius;
+  // from inside, the light is left to the BSDF samples
+  if dist_sq <= radius_sq {
+    return none;
+  }
+  let dist = dist_sq.sqrt();
+  let cos_max = (1.0 - radius_sq / dist_sq).sqrt();
+  let cos = 1.0 - rng.gen_pos() * (1.0 - cos_max);
+  let sin = (1.0 - cos * cos).max(0.0).sqrt();
+  let phi = 2.0 * PI * rng.gen_pos();
+  let w = to_center / dist;
+  let u = if w.x.abs() > 0.9 { Vec3::Y } else { Vec3::X };
+  let u = u.cross(w).normalize();
+  let v = w.cross(u);
+  let mat = &materials[sphere.mat as usize];
+  let solid_angle = 2.0 * PI * (1.0 - cos_max);
+  LightSample {
+    dir: (u * phi.cos() + v * phi.sin()) * sin + w * cos,
+    distance: dist * cos - (radius_sq - dist_sq * sin * sin).max(0.0).sqrt(),
+    light: mat.emission * mat.emission_strength * solid_angle * lights as f32,
+  }
+}
 
-    if closest.distance != f32::MAX {
-      let mat = materials[closest.mat];
-      let color = mat.truncate();
-      ray = match mat.w.into() {
-        Material::Lambertian => Ray::new(closest.pos, closest.normal + rng.gen_in_sphere()),
-        Material::Metal => Ray::new(closest.pos, reflect(ray.dir, closest.normal)),
-        Material::Emissive => {
-          *out_color += (color * attenuation).extend(1.0);
-          break;
-        }
-        Material::Dielectric => {
-          let ir = 1.5 + (wavelength - 150.0) * 0.0005;
-          let ir = if closest.front_face { 1.0 / ir } else { ir };
-          let cos_theta = (-ray.dir).dot(closest.normal).min(1.0);
-          let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
-
-          let cannot_refract = ir * sin_theta > 1.0;
-          let will_reflect = rng.gen_pos() < schlick(cos_theta, ir);
-          let dir = if cannot_refract || will_reflect {
-            reflect(ray.dir, closest.normal)
-          } else {
-            refract(ray.dir, closest.normal, ir)
-          };
-
-          Ray::new(closest.pos, dir)
+/// Light from `light` that the diffuse lobe of `mat` at `hit` reflects back
+/// along `ray`, before attenuation by the path.
+fn direct(mat: &Material, ray: &Ray, hit: &Hit, light: &LightSample) -> Vec3 {
+  let cos_theta = (-ray.dir).dot(hit.normal).min(1.0);
+  let cos_light = hit.normal.dot(light.dir).max(0.0);
+  diffuse(mat, cos_theta) / PI * cos_light * light.light
+}
+
+/// Whether nothing blocks `shadow` before it reaches a light `distance` away.
+fn unoccluded(
+  shadow: &Ray,
+  distance: f32,
+  spheres: &[Sphere],
+  instances: &[Instance],
+  vtx_buf: &[Vec4],
+  uv_buf: &[Vec2],
+  materials: &[Material],
+  sampler: &Sampler,
+  masks: &Image2dArray,
+  rng: &mut Rng,
+) -> bool {
+  let hit = trace(
+    shadow, spheres, instances, vtx_buf, uv_buf, materials, sampler, masks, rng,
+  );
+  // the light itself is hit at about `distance`
+  hit.distance >= distance * 0.999
+}
+
+/// Samples a reflection off an anisotropic GGX surface through its distribution
+/// of visible normals (Heitz 2018). `alpha` is the roughness along the tangent
+/// and the bitangent. Returns the reflected direction and the Smith masking
+/// term of that direction, which is the whole weight of the sample.
+fn ggx(v: Vec3, n: Vec3, t: Vec3, alpha: Vec2, rng: &mut Rng) -> (Vec3, f32) {
+  let alpha = alpha.max(Vec2::splat(1e-4));
+  let b = n.cross(t);
+  let wo = Vec3::new(-v.dot(t), -v.dot(b), -v.dot(n));
+
+  let vh = Vec3::new(alpha.x * wo.x, alpha.y * wo.y, wo.z).normalize();
+  let len_sq = vh.x * vh.x + vh.y * vh.y;
+  let t1 = if len_sq > 0.0 {
+    Vec3::new(-vh.y, vh.x, 0.0) / len_sq.sqrt()
+  } else {
+    Vec3::X
+  };
+  let t2 = vh.cross(t1);
+  let r = rng.gen_pos().sqrt();
+  let phi = 2.0 * PI * rng.gen_pos();
+  let p1 = r * phi.cos();
+  let s = 0.5 * (1.0 + vh.z);
+  let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+  let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+  let m = Vec3::new(alpha.x * nh.x, alpha.y * nh.y, nh.z.max(0.0)).normalize();
+
+  let dir = reflect(v, m.x * t + m.y * b + m.z * n);
+  let wi = Vec3::new(dir.dot(t), dir.dot(b), dir.dot(n));
+  if wi.z <= 0.0 {
+    return (dir, 0.0);
+  }
+  let tan_sq = (alpha.x * alpha.x * wi.x * wi.x + alpha.y * alpha.y * wi.y * wi.y) / (wi.z * wi.z);
+  (dir, 2.0 / (1.0 + (1.0 + tan_sq).sqrt()))
+}
+
+/// Whether a candidate hit blocks the ray, given the material's opacity at
+/// `uv`. Cut-out and transparent hits are skipped during traversal.
+fn opaque(
+  mat: &Material,
+  uv: Vec2,
+  sampler: &Sampler,
+  masks: &Image2dArray,
+  rng: &mut Rng,
+) -> bool {
+  if mat.alpha >= 1.0 && mat.alpha_texture == NO_TEXTURE {
+    return true;
+  }
+  let mut alpha = mat.alpha;
+  if mat.alpha_texture != NO_TEXTURE {
+    alpha *= masks
+      .sample_by_lod(*sampler, uv.extend(mat.alpha_texture as f32), 0.0)
+      .w;
+  }
+  if mat.alpha_cutoff > 0.0 {
+    alpha >= mat.alpha_cutoff
+  } else {
+    rng.gen_pos() < alpha
+  }
+}
+
+/// Tangent running around the Y axis, so brushing lines up across spheres and
+/// meshes that have no texture coordinates to derive one from.
+fn tangent(normal: Vec3) -> Vec3 {
+  let t = Vec3::Y.cross(normal);
+  if t.length_squared() > 1e-8 {
+    t.normalize()
+  } else {
+    Vec3::X
+  }
+}
+
+/// Finds the closest opaque hit along `ray`. Misses have a distance of
+/// `f32::MAX`.
+fn trace(
+  ray: &Ray,
+  spheres: &[Sphere],
+  instances: &[Instance],
+  vtx_buf: &[Vec4],
+  uv_buf: &[Vec2],
+  materials: &[Material],
+  sampler: &Sampler,
+  masks: &Image2dArray,
+  rng: &mut Rng,
+) -> Hit {
+  let mut closest = Hit::default();
+  closest.distance = f32::MAX;
+  for i in 0..spheres.len() {
+    let mut hit = spheres[i].hit(ray, 0.001, closest.distance);
+    if hit.distance > 0.0 && !opaque(&materials[hit.mat], hit.uv, sampler, masks, rng) {
+      // cut away in front, the far side may still be there
+      hit = spheres[i].hit(ray, hit.distance + 0.001, closest.distance);
+      if hit.distance > 0.0 && !opaque(&materials[hit.mat], hit.uv, sampler, masks, rng) {
+        continue;
+      }
+    }
+    if hit.distance > 0.0 {
+      closest = hit;
+    }
+  }
+  for i in 0..instances.len() {
+    let inst = &instances[i];
+    // instances move rigidly, so distances are the same in object space
+    let pos = inst.pos.lerp(inst.pos_end, ray.time);
+    let rot = inst.rot.slerp(inst.rot_end, ray.time);
+    let local = Ray {
+      origin: rot.inverse() * (ray.origin - pos),
+      dir: rot.inverse() * ray.dir,
+      time: ray.time,
+    };
+    if !AABB(inst.min, inst.max).hit(&local) {
+      continue;
+    }
+    for f in inst.start as usize..inst.end as usize {
+      let mut hit = Tri(
+        vtx_buf[3 * f].truncate(),
+        vtx_buf[3 * f + 1].truncate(),
+        vtx_buf[3 * f + 2].truncate(),
+        inst.mat as usize,
+      )
+      .hit(&local, 0.001, closest.distance);
+      if hit.distance > 0.0 {
+        hit.uv = (1.0 - hit.uv.x - hit.uv.y) * uv_buf[3 * f]
+          + hit.uv.x * uv_buf[3 * f + 1]
+          + hit.uv.y * uv_buf[3 * f + 2];
+        if opaque(&materials[hit.mat], hit.uv, sampler, masks, rng) {
+          hit.pos = ray.at(hit.distance);
+          // the mesh keeps the tangent from its texture coordinates in the w
+          // of the vertices, made perpendicular to the normal here
+          let t = Vec3::new(vtx_buf[3 * f].w, vtx_buf[3 * f + 1].w, vtx_buf[3 * f + 2].w);
+          let t = t - hit.normal * hit.normal.dot(t);
+          if t.length_squared() > 1e-8 {
+            hit.tangent = t.normalize();
+          }
+          hit.normal = rot * hit.normal;
+          hit.tangent = rot * hit.tangent;
+          closest = hit;
         }
-      };
-      attenuation *= color;
-    } else {
-      *out_color +=
-        sky.sample_by_lod(*sampler, to_equirect(ray.dir), 1.0) * attenuation.extend(1.0);
-      break;
+      }
     }
   }
+  closest
 }
 
-fn to_equirect(dir: Vec3) -> Vec2 {
-  Vec2::new(dir.z.atan2(dir.x) + PI, dir.y.acos()) / Vec2::new(2.0 * PI, PI)
+/// Traces a single primary ray through the pixel at `consts.probe`, without
+/// defocus, and outputs the focus distance of whatever it hits or zero on a
+/// miss. Used to focus the camera on a clicked point.
+#[spirv(fragment)]
+pub fn focus_f(
+  #[spirv(uniform, descriptor_set = 0, binding = 0)] consts: &Consts,
+  #[spirv(descriptor_set = 1, binding = 0)] sampler: &Sampler,
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 0)] vtx_buf: &mut [Vec4],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 1)] materials: &mut [Material],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 2)] uv_buf: &mut [Vec2],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 3)] spheres: &mut [Sphere],
+  #[spirv(storage_buffer, descriptor_set = 4, binding = 4)] instances: &mut [Instance],
+  #[spirv(descriptor_set = 5, binding = 0)] masks: &Image2dArray,
+  out_distance: &mut f32,
+) {
+  let mut rng = Rng(consts.rand);
+  let cam = Camera::new(consts, consts.probe, consts.shutter_open);
+  let ray = cam.primary(Vec2::ZERO);
+  let hit = trace(
+    &ray, spheres, instances, vtx_buf, uv_buf, materials, sampler, masks, &mut rng,
+  );
+  *out_distance = if hit.distance != f32::MAX {
+    hit.distance * ray.dir.dot(cam.forward)
+  } else {
+    0.0
+  };
 }
 
-fn unreal(x: Vec3) -> Vec3 {
-  x / (x + 0.155) * 1.019
+fn to_equirect(dir: Vec3) -> Vec2 {
+  Vec2::new(dir.z.atan2(dir.x) + PI, dir.y.acos()) / Vec2::new(2.0 * PI, PI)
 }
 
 #[spirv(fragment)]
 pub fn quad_f(
-  uv: Vec2,
+  #[spirv(frag_coord)] frag_coord: Vec4,
   #[spirv(uniform, descriptor_set = 0, binding = 0)] consts: &Consts,
   #[spirv(descriptor_set = 1, binding = 0)] sampler: &Sampler,
   #[spirv(descriptor_set = 2, binding = 0)] tex: &Image2d,
   out_color: &mut Vec4,
 ) {
-  *out_color =
-    unreal(tex.sample(*sampler, Vec2::new(uv.x, 1.0 - uv.y)).truncate() / consts.samples as f32)
-      .extend(1.0);
+  let pos = (Vec2::new(frag_coord.x, frag_coord.y) - consts.view_offset) / consts.view_scale;
+  if pos.cmplt(Vec2::ZERO).any() || pos.cmpge(consts.size).any() {
+    *out_color = Vec4::W;
+    return;
+  }
+  // zoomed in, show whole pixels instead of blending them
+  let sum = if consts.view_scale >= 1.0 {
+    tex.fetch(pos.as_ivec2())
+  } else {
+    tex.sample(*sampler, pos / consts.size)
+  };
+  let aov = Aov::from(consts.aov);
+  let color = sum.truncate() / sum.w.max(1.0);
+  *out_color = if aov == Aov::Samples {
+    heat(sum.w / (consts.samples as f32 - 1.0).max(1.0))
+  } else if aov.is_radiance() {
+    unreal(color)
+  } else {
+    color
+  }
+  .extend(1.0);
+}
+
+// the denoiser's edge-stopping functions, see `dn_filter`
+const SIGMA_LUMINANCE: f32 = 4.0;
+const SIGMA_NORMAL: f32 = 128.0;
+const SIGMA_DEPTH: f32 = 0.02;
+/// Least albedo divided out of the radiance, to keep black surfaces finite.
+const MIN_ALBEDO: f32 = 0.01;
+
+/// Averages the samples of the tile and divides out the albedo, for the
+/// denoiser to filter the lighting and leave the texture detail alone. Keeps
+/// the variance of the average in alpha.
+#[spirv(compute(threads(8, 8)))]
+pub fn dn_prepare(
+  #[spirv(global_invocation_id)] id: UVec3,
+  #[spirv(uniform, descriptor_set = 0, binding = 0)] consts: &Consts,
+  #[spirv(descriptor_set = 1, binding = 0)] sums: &Image2d,
+  #[spirv(descriptor_set = 1, binding = 1)] moments: &Image2d,
+  #[spirv(descriptor_set = 1, binding = 2)] aovs: &Image2dArray,
+  #[spirv(descriptor_set = 2, binding = 2)] output: &Filtered,
+) {
+  let px = id.truncate();
+  if px.as_vec2().cmpge(consts.tile_size).any() {
+    return;
+  }
+  let sum = sums.fetch(px.as_ivec2());
+  let n = sum.w.max(1.0);
+  let albedo = aovs
+    .fetch(px.as_ivec2().extend(ALBEDO_LAYER as i32))
+    .truncate();
+  let albedo = (albedo / n).max(Vec3::splat(MIN_ALBEDO));
+  let mean = luminance(sum.truncate()) / n;
+  let variance = (moments.fetch(px.as_ivec2()).x / n - mean * mean).max(0.0) / n;
+  let irradiance = sum.truncate() / n / albedo;
+  let scale = luminance(irradiance) / mean.max(1e-6);
+  unsafe {
+    output.write(px, irradiance.extend(variance * scale * scale));
+  }
+}
+
+/// One pass of the edge-avoiding à-trous wavelet filter: a 5x5 B3 spline
+/// with `pass.step` pixels between its taps, weighted down across edges in
+/// the normals and depth, and across differences in luminance the variance
+/// doesn't explain. The variance is filtered along with the squared weights
+/// for the next pass. The last pass multiplies the albedo back in and
+/// writes sums like the path tracer's, for `quad_f` to show.
+#[spirv(compute(threads(8, 8)))]
+pub fn dn_filter(
+  #[spirv(global_invocation_id)] id: UVec3,
+  #[spirv(uniform, descriptor_set = 0, binding = 0)] consts: &Consts,
+  #[spirv(descriptor_set = 1, binding = 0)] sums: &Image2d,
+  #[spirv(descriptor_set = 1, binding = 2)] aovs: &Image2dArray,
+  #[spirv(uniform, descriptor_set = 2, binding = 0)] pass: &DenoisePass,
+  #[spirv(descriptor_set = 2, binding = 1)] input: &Image2d,
+  #[spirv(descriptor_set = 2, binding = 2)] output: &Filtered,
+) {
+  let px = id.truncate();
+  let size = consts.tile_size.as_ivec2();
+  if px.as_ivec2().cmpge(size).any() {
+    return;
+  }
+  let p = px.as_ivec2();
+  let n = sums.fetch(p).w.max(1.0);
+  let guide = aovs.fetch(p.extend(NORMAL_LAYER as i32)) / n;
+  let (normal, depth) = (guide.truncate().normalize_or_zero(), guide.w);
+  let center = input.fetch(p);
+  let lum = luminance(center.truncate());
+  let sigma_l = SIGMA_LUMINANCE * center.w.sqrt() + 1e-4;
+  let step = pass.step as i32;
+
+  let mut color = Vec3::ZERO;
+  let mut variance = 0.0;
+  let mut total = 0.0;
+  for y in 0..5 {
+    for x in 0..5 {
+      let offset = IVec2::new(x - 2, y - 2);
+      let q = p + offset * step;
+      if q.cmplt(IVec2::ZERO).any() || q.cmpge(size).any() {
+        continue;
+      }
+      let sample = input.fetch(q);
+      let n_q = sums.fetch(q).w.max(1.0);
+      let guide_q = aovs.fetch(q.extend(NORMAL_LAYER as i32)) / n_q;
+      let normal_q = guide_q.truncate().normalize_or_zero();
+      let w_normal = if normal == Vec3::ZERO || normal_q == Vec3::ZERO {
+        1.0
+      } else {
+        normal.dot(normal_q).max(0.0).powf(SIGMA_NORMAL)
+      };
+      let w_depth =
+        (-(depth - guide_q.w).abs() / (SIGMA_DEPTH * depth.max(1e-3) * step as f32)).exp();
+      let w_lum = (-(lum - luminance(sample.truncate())).abs() / sigma_l).exp();
+      let w = b3(offset.x) * b3(offset.y) * w_normal * w_depth * w_lum;
+      color += sample.truncate() * w;
+      variance += sample.w * w * w;
+      total += w;
+    }
+  }
+  color /= total.max(1e-6);
+  variance /= (total * total).max(1e-12);
+  let out = if pass.last != 0 {
+    let albedo = aovs.fetch(p.extend(ALBEDO_LAYER as i32)).truncate();
+    let albedo = (albedo / n).max(Vec3::splat(MIN_ALBEDO));
+    (color * albedo * n).extend(n)
+  } else {
+    color.extend(variance)
+  };
+  unsafe {
+    output.write(px, out);
+  }
+}
+
+/// Weight of the B3 spline at `offset` taps from its center.
+fn b3(offset: i32) -> f32 {
+  match offset.abs() {
+    0 => 3.0 / 8.0,
+    1 => 1.0 / 4.0,
+    _ => 1.0 / 16.0,
+  }
+}
+
+/// Writes the AOV picked by `consts.aov` as sums like the path tracer's, for
+/// `quad_f` to show in place of the image. Directions and positions are
+/// mapped to colors, and distances to shades of gray.
+#[spirv(compute(threads(8, 8)))]
+pub fn aov_show(
+  #[spirv(global_invocation_id)] id: UVec3,
+  #[spirv(uniform, descriptor_set = 0, binding = 0)] consts: &Consts,
+  #[spirv(descriptor_set = 1, binding = 0)] sums: &Image2d,
+  #[spirv(descriptor_set = 1, binding = 1)] aovs: &Image2dArray,
+  #[spirv(descriptor_set = 1, binding = 2)] output: &Filtered,
+) {
+  let px = id.truncate();
+  if px.as_vec2().cmpge(consts.tile_size).any() {
+    return;
+  }
+  let p = px.as_ivec2();
+  let n = sums.fetch(p).w.max(1.0);
+  let layer = match Aov::from(consts.aov) {
+    Aov::Normal | Aov::Depth => NORMAL_LAYER,
+    Aov::Position | Aov::Material => POSITION_LAYER,
+    Aov::Direct => DIRECT_LAYER,
+    Aov::Indirect => INDIRECT_LAYER,
+    Aov::Emission => EMISSION_LAYER,
+    _ => ALBEDO_LAYER,
+  };
+  let sum = aovs.fetch(p.extend(layer as i32));
+  let value = sum / n;
+  let color = match Aov::from(consts.aov) {
+    Aov::Normal => value.truncate().normalize_or_zero() * 0.5 + 0.5,
+    Aov::Depth => Vec3::splat(1.0 / (1.0 + value.w)),
+    Aov::Position => value.truncate().fract(),
+    Aov::Material if sum.w == 0.0 => Vec3::ZERO,
+    // alpha isn't summed for the material
+    Aov::Material => {
+      let h = hash(sum.w as u32);
+      Vec3::new(
+        (h & 0xFF) as f32,
+        (h >> 8 & 0xFF) as f32,
+        (h >> 16 & 0xFF) as f32,
+      ) / 255.0
+    }
+    _ => value.truncate(),
+  };
+  unsafe {
+    output.write(px, (color * n).extend(n));
+  }
+}
+
+/// Blue through green to red as `t` goes from 0 to 1.
+fn heat(t: f32) -> Vec3 {
+  let t = t.clamp(0.0, 1.0);
+  Vec3::new(
+    (t * 2.0 - 1.0).max(0.0),
+    1.0 - (t * 2.0 - 1.0).abs(),
+    (1.0 - t * 2.0).max(0.0),
+  )
 }
 
 struct Rng(u32);
@@ -376,6 +1539,21 @@ impl Rng {
     self.gen_pos().sqrt() * Vec2::new(t.cos(), t.sin())
   }
 
+  /// Uniform point in the regular polygon with `sides` corners on the unit
+  /// circle, the first at angle `rotation`.
+  fn gen_in_polygon(&mut self, sides: u32, rotation: f32) -> Vec2 {
+    let step = 2.0 * PI / sides as f32;
+    let side = (self.gen_pos() * sides as f32).floor();
+    let a = rotation + side * step;
+    let b = a + step;
+    let (mut u, mut v) = (self.gen_pos(), self.gen_pos());
+    if u + v > 1.0 {
+      u = 1.0 - u;
+      v = 1.0 - v;
+    }
+    u * Vec2::new(a.cos(), a.sin()) + v * Vec2::new(b.cos(), b.sin())
+  }
+
   fn gen_in_sphere(&mut self) -> Vec3 {
     let u = self.gen();
     let v = self.gen();
@@ -385,6 +1563,32 @@ impl Rng {
   }
 }
 
+/// Samples a point on the aperture mask in proportion to its brightness, by
+/// inverting the CDFs built on the host: one over the rows, followed by one
+/// over each row. The mask covers the square from -1 to 1.
+fn sample_mask(cdf: &[f32], size: UVec2, rng: &mut Rng) -> Vec2 {
+  let (width, height) = (size.x as usize, size.y as usize);
+  let y = search(cdf, 0, height, rng.gen_pos());
+  let x = search(cdf, height + y * width, width, rng.gen_pos());
+  let p = Vec2::new(x as f32 + rng.gen_pos(), y as f32 + rng.gen_pos()) / size.as_vec2();
+  Vec2::new(2.0 * p.x - 1.0, 1.0 - 2.0 * p.y)
+}
+
+/// Index of the first entry of `cdf[start..start + len]` at or above `u`.
+fn search(cdf: &[f32], start: usize, len: usize, u: f32) -> usize {
+  let mut lo = 0;
+  let mut hi = len - 1;
+  while lo < hi {
+    let mid = (lo + hi) / 2;
+    if cdf[start + mid] < u {
+      lo = mid + 1;
+    } else {
+      hi = mid;
+    }
+  }
+  lo
+}
+
 fn reflect(v: Vec3, n: Vec3) -> Vec3 {
   v - 2.0 * v.dot(n) * n
 }
@@ -412,8 +1616,8 @@ pub fn ui_v(
   out_color: &mut Vec3,
 ) {
   *out_pos = Vec4::new(
-    2.0 * pos.x / consts.size.x - 1.0,
-    1.0 - 2.0 * pos.y / consts.size.y,
+    2.0 * pos.x / consts.window.x - 1.0,
+    1.0 - 2.0 * pos.y / consts.window.y,
     0.0,
     1.0,
   );
@@ -1,16 +1,165 @@
 #![no_std]
-use core::mem;
-use glam::{Vec2, Vec3};
+use glam::{Quat, UVec2, Vec2, Vec3};
 
 #[repr(C, align(16))]
 #[cfg_attr(not(target_arch = "spirv"), derive(Copy, Clone, Debug))]
 pub struct Consts {
+  /// Resolution of the render.
   pub size: Vec2,
   pub rand: u32,
   pub samples: u32,
+  pub cam_pos: Vec3,
   pub zero: f32,
+  /// Pixel traced by the focus probe.
+  pub probe: Vec2,
+  pub cam_yaw: f32,
+  pub cam_pitch: f32,
+  pub cam_fov: f32,
+  pub cam_aperture: f32,
+  pub cam_focus: f32,
+  pub cam_projection: u32,
+  /// Number of aperture blades, fewer than three give a round aperture.
+  pub cam_blades: u32,
+  pub cam_blade_rotation: f32,
+  /// Size of the aperture mask, zero if there is none.
+  pub bokeh_size: UVec2,
+  /// Camera pose at the end of the frame, the one above is at the start.
+  pub cam_pos_end: Vec3,
+  pub cam_yaw_end: f32,
+  pub cam_pitch_end: f32,
+  /// Part of the frame the shutter is open for, as fractions from 0 to 1.
+  pub shutter_open: f32,
+  pub shutter_close: f32,
+  /// Keeps `window` 8-byte aligned for the shader.
+  pub padding: f32,
+  /// Size of the window the render and UI are drawn to.
+  pub window: Vec2,
+  /// Window position of the render's top left corner.
+  pub view_offset: Vec2,
+  /// Pixel of the whole image at the top left of the tile being rendered.
+  pub tile_offset: Vec2,
+  /// Part of the tile inside the image, the pixels that get traced.
+  pub tile_size: Vec2,
+  /// Window pixels per render pixel.
+  pub view_scale: f32,
+  /// Samples per pixel traced by each dispatch.
+  pub batch: u32,
+  /// Relative standard error below which a pixel counts as converged and
+  /// gets no more samples. Zero samples every pixel.
+  pub noise: f32,
+  /// What the window shows, an `Aov`.
+  pub aov: u32,
+  /// Half the height of the orthographic view.
+  pub cam_ortho_size: f32,
 }
 
+/// Samples a pixel whose samples have all come out the same needs before it
+/// counts as converged, since a zero variance more likely means the rare
+/// paths to a small light haven't turned up yet than that there are none.
+pub const FLAT_SAMPLES: f32 = 256.0;
+
+#[repr(u32)]
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+pub enum Projection {
+  /// Thin lens perspective, the only one with depth of field.
+  Perspective,
+  Orthographic,
+  /// Full 360° panorama around the camera.
+  Equirect,
+  /// Equidistant fisheye.
+  Fisheye,
+}
+
+impl From<u32> for Projection {
+  fn from(v: u32) -> Self {
+    match v {
+      1 => Self::Orthographic,
+      2 => Self::Equirect,
+      3 => Self::Fisheye,
+      _ => Self::Perspective,
+    }
+  }
+}
+
+/// What can be shown in the window besides the render.
+#[repr(u32)]
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+pub enum Aov {
+  Image,
+  /// Heatmap of the samples taken in each pixel.
+  Samples,
+  Albedo,
+  Normal,
+  Depth,
+  Position,
+  Material,
+  /// Light reaching the first hit straight from an emitter or the sky.
+  Direct,
+  /// Light reaching the first hit after more bounces.
+  Indirect,
+  /// Emitters and sky seen straight from the camera.
+  Emission,
+}
+
+impl Aov {
+  pub const ALL: [Self; 10] = [
+    Self::Image,
+    Self::Samples,
+    Self::Albedo,
+    Self::Normal,
+    Self::Depth,
+    Self::Position,
+    Self::Material,
+    Self::Direct,
+    Self::Indirect,
+    Self::Emission,
+  ];
+
+  /// Whether the AOV is light, to be tonemapped like the image when shown,
+  /// rather than values shown as they are.
+  pub fn is_radiance(self) -> bool {
+    matches!(
+      self,
+      Self::Image | Self::Direct | Self::Indirect | Self::Emission
+    )
+  }
+}
+
+impl From<u32> for Aov {
+  fn from(v: u32) -> Self {
+    match v {
+      1 => Self::Samples,
+      2 => Self::Albedo,
+      3 => Self::Normal,
+      4 => Self::Depth,
+      5 => Self::Position,
+      6 => Self::Material,
+      7 => Self::Direct,
+      8 => Self::Indirect,
+      9 => Self::Emission,
+      _ => Self::Image,
+    }
+  }
+}
+
+// layers of the AOV texture array, summed over the samples like the image
+/// Albedo of the first hit, white for the sky.
+pub const ALBEDO_LAYER: u32 = 0;
+/// Normal of the first hit, with the distance to it in alpha.
+pub const NORMAL_LAYER: u32 = 1;
+/// Position of the first hit. Alpha isn't summed but holds the index of the
+/// material of the first sample plus one, zero for the sky.
+pub const POSITION_LAYER: u32 = 2;
+/// Light reaching the camera after exactly one bounce.
+pub const DIRECT_LAYER: u32 = 3;
+/// Light reaching the camera after two or more bounces.
+pub const INDIRECT_LAYER: u32 = 4;
+/// Light reaching the camera without bouncing.
+pub const EMISSION_LAYER: u32 = 5;
+pub const AOV_LAYERS: u32 = 6;
+
 #[repr(C)]
 #[cfg_attr(not(target_arch = "spirv"), derive(Copy, Clone, Debug))]
 pub struct Vertex {
@@ -19,17 +168,233 @@ pub struct Vertex {
   pub color: Vec3,
 }
 
-#[repr(u32)]
-#[derive(Copy, Clone)]
-pub enum Material {
-  Lambertian,
-  Metal,
-  Emissive,
-  Dielectric,
+pub const NO_TEXTURE: u32 = u32::MAX;
+
+/// Settings of one pass of the denoiser's wavelet filter.
+#[repr(C, align(16))]
+#[cfg_attr(not(target_arch = "spirv"), derive(Copy, Clone, Debug))]
+pub struct DenoisePass {
+  /// Pixels between the taps of the filter.
+  pub step: u32,
+  /// Whether this is the last pass, which multiplies the albedo back in.
+  pub last: u32,
+  pub padding: [u32; 2],
+}
+
+/// Tonemaps linear radiance for display, gamma included. Shared so exported
+/// images look the same as the window.
+pub fn unreal(x: Vec3) -> Vec3 {
+  x / (x + 0.155) * 1.019
+}
+
+/// Relative luminance of linear Rec. 709 radiance.
+pub fn luminance(color: Vec3) -> f32 {
+  color.dot(Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+/// Sphere moving in a straight line from `pos` at the start of the frame to
+/// `pos_end` at the end.
+#[repr(C, align(16))]
+#[cfg_attr(not(target_arch = "spirv"), derive(Copy, Clone, Debug))]
+pub struct Sphere {
+  pub pos: Vec3,
+  pub radius: f32,
+  pub pos_end: Vec3,
+  pub mat: u32,
+}
+
+/// Placement of the triangles `start..end` of the vertex buffer, moving
+/// rigidly from `rot` and `pos` at the start of the frame to `rot_end` and
+/// `pos_end` at the end. `min` and `max` bound the untransformed triangles.
+#[repr(C, align(16))]
+#[cfg_attr(not(target_arch = "spirv"), derive(Copy, Clone, Debug))]
+pub struct Instance {
+  pub rot: Quat,
+  pub rot_end: Quat,
+  pub pos: Vec3,
+  pub mat: u32,
+  pub pos_end: Vec3,
+  pub start: u32,
+  pub min: Vec3,
+  pub end: u32,
+  pub max: Vec3,
 }
 
-impl From<f32> for Material {
-  fn from(f: f32) -> Self {
-    unsafe { mem::transmute(f) }
+/// Bounces a path takes at most.
+pub const MAX_BOUNCES: u32 = 32;
+
+/// Queues of the wavefront integrator: rays to trace, followed by hits for
+/// the glossy, transmission and diffuse shading kernels, and shadow rays.
+pub const QUEUES: usize = 5;
+/// `u32`s in front of the queues in their buffer for each queue's counters:
+/// the number of entries pushed, the workgroups to dispatch for them as
+/// indirect arguments, and the number of entries being consumed.
+pub const QUEUE_HEADER: usize = 8;
+/// Threads per workgroup of the kernels that consume a queue.
+pub const QUEUE_WORKGROUP: u32 = 64;
+
+/// A path in flight between the kernels of the wavefront integrator. Once
+/// traced, `origin` is where it hit and `normal` through `lobe` describe the
+/// hit for shading.
+#[repr(C, align(16))]
+#[cfg_attr(not(target_arch = "spirv"), derive(Copy, Clone, Debug))]
+pub struct Path {
+  pub origin: Vec3,
+  pub time: f32,
+  pub dir: Vec3,
+  pub wavelength: f32,
+  pub attenuation: Vec3,
+  pub rng: u32,
+  pub color: Vec3,
+  /// Index of the pixel in the tile, row by row.
+  pub pixel: u32,
+  pub normal: Vec3,
+  pub mat: u32,
+  pub tangent: Vec3,
+  pub bounces: u32,
+  pub front_face: u32,
+  pub lobe: u32,
+  /// Keeps `shadow_dir` and `shadow_light` at the start of a `Vec4`, where a
+  /// storage buffer needs them.
+  pub padding: [u32; 2],
+  /// Shadow ray towards the light sampled at the last diffuse hit, and the
+  /// light it brings if nothing is in the way.
+  pub shadow_dir: Vec3,
+  pub shadow_distance: f32,
+  pub shadow_light: Vec3,
+  /// Whether the last hit sampled a light, so hitting it next doesn't count.
+  pub nee: u32,
+}
+
+/// Principled BSDF parameters, following Blender's Principled BSDF and glTF's
+/// metallic-roughness model. Every `Vec3` is followed by an `f32` so the
+/// record packs into whole `Vec4`s and can be indexed straight out of a
+/// storage buffer.
+#[repr(C, align(16))]
+#[cfg_attr(not(target_arch = "spirv"), derive(Copy, Clone, Debug))]
+pub struct Material {
+  pub base_color: Vec3,
+  pub metallic: f32,
+  pub emission: Vec3,
+  pub roughness: f32,
+  pub specular: f32,
+  pub transmission: f32,
+  pub ior: f32,
+  pub sheen: f32,
+  pub sheen_tint: f32,
+  pub clearcoat: f32,
+  pub clearcoat_roughness: f32,
+  pub emission_strength: f32,
+  pub anisotropic: f32,
+  pub anisotropic_rotation: f32,
+  /// Opacity, multiplied with the alpha of `alpha_texture` if it is set.
+  pub alpha: f32,
+  /// Hits with alpha below this are cut out and the rest are opaque, like
+  /// glTF's `MASK` mode. At zero, fractional alpha is resolved stochastically.
+  pub alpha_cutoff: f32,
+  /// Layer of the mask texture array, or `NO_TEXTURE`.
+  pub alpha_texture: u32,
+}
+
+impl Material {
+  /// Blender's defaults for a new Principled BSDF.
+  pub const DEFAULT: Self = Self {
+    base_color: Vec3::splat(0.8),
+    metallic: 0.0,
+    emission: Vec3::ZERO,
+    roughness: 0.5,
+    specular: 0.5,
+    transmission: 0.0,
+    ior: 1.45,
+    sheen: 0.0,
+    sheen_tint: 0.5,
+    clearcoat: 0.0,
+    clearcoat_roughness: 0.03,
+    emission_strength: 1.0,
+    anisotropic: 0.0,
+    anisotropic_rotation: 0.0,
+    alpha: 1.0,
+    alpha_cutoff: 0.0,
+    alpha_texture: NO_TEXTURE,
+  };
+
+  pub const fn lambertian(color: Vec3) -> Self {
+    Self {
+      base_color: color,
+      roughness: 1.0,
+      specular: 0.0,
+      ..Self::DEFAULT
+    }
+  }
+
+  pub const fn metal(color: Vec3, roughness: f32) -> Self {
+    Self {
+      base_color: color,
+      metallic: 1.0,
+      roughness,
+      ..Self::DEFAULT
+    }
+  }
+
+  pub const fn emissive(color: Vec3, strength: f32) -> Self {
+    Self {
+      base_color: Vec3::ZERO,
+      emission: color,
+      emission_strength: strength,
+      ..Self::DEFAULT
+    }
+  }
+
+  pub const fn dielectric(color: Vec3, ior: f32) -> Self {
+    Self {
+      base_color: color,
+      roughness: 0.0,
+      transmission: 1.0,
+      ior,
+      ..Self::DEFAULT
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use core::mem::MaybeUninit;
+  use core::ptr::addr_of;
+
+  /// Byte offset of `field` in `T`.
+  macro_rules! offset {
+    ($t:ty, $field:ident) => {{
+      let value = MaybeUninit::<$t>::uninit();
+      let base = value.as_ptr();
+      unsafe { addr_of!((*base).$field) as usize - base as usize }
+    }};
+  }
+
+  #[test]
+  fn storage_vec3s_start_a_vec4() {
+    let offsets = [
+      offset!(Sphere, pos),
+      offset!(Sphere, pos_end),
+      offset!(Instance, pos),
+      offset!(Instance, pos_end),
+      offset!(Instance, min),
+      offset!(Instance, max),
+      offset!(Path, origin),
+      offset!(Path, dir),
+      offset!(Path, attenuation),
+      offset!(Path, color),
+      offset!(Path, normal),
+      offset!(Path, tangent),
+      offset!(Path, shadow_dir),
+      offset!(Path, shadow_light),
+      offset!(Material, base_color),
+      offset!(Material, emission),
+    ];
+    for offset in offsets {
+      assert_eq!(offset % 16, 0, "Vec3 at {}", offset);
+    }
+    assert_eq!(offset!(Path, shadow_dir), 112);
+    assert_eq!(offset!(Path, shadow_light), 128);
   }
 }
@@ -0,0 +1,219 @@
+use exr::prelude::{AnyChannel, AnyChannels, Encoding, FlatSamples, Image, ImageAttributes};
+use exr::prelude::{IntegerBounds, Layer, LayerAttributes, WritableImage};
+use glam::{UVec2, Vec3};
+use shared::{ALBEDO_LAYER, DIRECT_LAYER, EMISSION_LAYER, INDIRECT_LAYER, NORMAL_LAYER};
+use shared::POSITION_LAYER;
+use crate::renderer::{self, layout, WORKGROUP};
+use crate::Result;
+
+/// Shows the AOV picked by `consts.aov` in the window, written out like the
+/// sums so the quad can draw it in their place.
+pub struct AovView {
+  pipeline: wgpu::ComputePipeline,
+  inputs: wgpu::BindGroup,
+  /// The AOV, sums with the sample count in alpha like
+  /// `Textures::bind_group`.
+  pub bind_group: wgpu::BindGroup,
+}
+
+impl AovView {
+  /// Sets up showing the AOVs of a canvas of `canvas` pixels. `sources` are
+  /// the sums and AOVs written by the path tracer. `uniform` is the layout of
+  /// the consts, and `texture` that of a sampled texture to show the result
+  /// with.
+  pub fn new(
+    device: &wgpu::Device,
+    shader: &wgpu::ShaderModule,
+    uniform: &wgpu::BindGroupLayout,
+    texture: &wgpu::BindGroupLayout,
+    sources: [&wgpu::Texture; 2],
+    canvas: UVec2,
+  ) -> Self {
+    let unfilterable = |view_dimension| wgpu::BindingType::Texture {
+      sample_type: wgpu::TextureSampleType::Float { filterable: false },
+      view_dimension,
+      multisampled: false,
+    };
+    let inputs_layout = layout(
+      device,
+      wgpu::ShaderStages::COMPUTE,
+      &[
+        unfilterable(wgpu::TextureViewDimension::D2),
+        unfilterable(wgpu::TextureViewDimension::D2Array),
+        wgpu::BindingType::StorageTexture {
+          access: wgpu::StorageTextureAccess::WriteOnly,
+          format: wgpu::TextureFormat::Rgba32Float,
+          view_dimension: wgpu::TextureViewDimension::D2,
+        },
+      ],
+    );
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+      bind_group_layouts: &[uniform, &inputs_layout],
+      push_constant_ranges: &[],
+      label: None,
+    });
+    let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+      layout: Some(&pipeline_layout),
+      module: shader,
+      entry_point: "aov_show",
+      label: None,
+    });
+
+    let out = device
+      .create_texture(&wgpu::TextureDescriptor {
+        size: wgpu::Extent3d {
+          width: canvas.x,
+          height: canvas.y,
+          depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba32Float,
+        usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+        label: None,
+      })
+      .create_view(&wgpu::TextureViewDescriptor::default());
+    let [sums, aovs] = sources.map(|t| t.create_view(&wgpu::TextureViewDescriptor::default()));
+    let inputs = device.create_bind_group(&wgpu::BindGroupDescriptor {
+      layout: &inputs_layout,
+      entries: &[
+        wgpu::BindGroupEntry {
+          binding: 0,
+          resource: wgpu::BindingResource::TextureView(&sums),
+        },
+        wgpu::BindGroupEntry {
+          binding: 1,
+          resource: wgpu::BindingResource::TextureView(&aovs),
+        },
+        wgpu::BindGroupEntry {
+          binding: 2,
+          resource: wgpu::BindingResource::TextureView(&out),
+        },
+      ],
+      label: None,
+    });
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+      layout: texture,
+      entries: &[wgpu::BindGroupEntry {
+        binding: 0,
+        resource: wgpu::BindingResource::TextureView(&out),
+      }],
+      label: None,
+    });
+    Self {
+      pipeline,
+      inputs,
+      bind_group,
+    }
+  }
+
+  /// Records writing the AOV of a tile of `size` into `bind_group`.
+  pub fn show(
+    &self,
+    encoder: &mut wgpu::CommandEncoder,
+    uniform_bind_group: &wgpu::BindGroup,
+    size: UVec2,
+  ) {
+    let groups = (size + WORKGROUP - 1) / WORKGROUP;
+    let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+    pass.set_pipeline(&self.pipeline);
+    pass.set_bind_group(0, uniform_bind_group, &[]);
+    pass.set_bind_group(1, &self.inputs, &[]);
+    pass.dispatch_workgroups(groups.x, groups.y, 1);
+  }
+}
+
+/// Writes the image averaged from `sums` to `path` like `renderer::save`.
+/// OpenEXR files also get the AOVs averaged from `aovs` as more layers, both
+/// laid out like `Renderer::read_raw` and `Renderer::read_aovs` return them.
+pub fn save(sums: &[f32], aovs: Option<&[f32]>, size: UVec2, path: &str, depth: u32) -> Result {
+  let image = renderer::average(sums.to_vec(), size);
+  let ext = std::path::Path::new(path).extension().unwrap_or_default();
+  let Some(aovs) = aovs.filter(|_| ext.eq_ignore_ascii_case("exr")) else {
+    return renderer::save(&image, path, depth);
+  };
+
+  let pixels = (size.x * size.y) as usize;
+  let aov = |layer: u32| &aovs[layer as usize * pixels * 4..][..pixels * 4];
+  // each channel of the AOVs is averaged like the image
+  let average = |data: &[f32], c: usize| {
+    (0..pixels)
+      .map(|i| data[i * 4 + c] / sums[i * 4 + 3].max(1.0))
+      .collect::<Vec<_>>()
+  };
+  let channels = |names: &[&str], values: &dyn Fn(usize) -> Vec<f32>| {
+    let list = names
+      .iter()
+      .enumerate()
+      .map(|(c, name)| AnyChannel::new(*name, FlatSamples::F32(values(c))))
+      .collect::<Vec<_>>();
+    AnyChannels::sort(list.into())
+  };
+  let layer = |name: Option<&str>, channels| {
+    let attributes = match name {
+      Some(name) => LayerAttributes::named(name),
+      None => LayerAttributes::default(),
+    };
+    Layer::new(
+      (size.x as usize, size.y as usize),
+      attributes,
+      Encoding::FAST_LOSSLESS,
+      channels,
+    )
+  };
+  let rgb = ["R", "G", "B"];
+  let xyz = ["X", "Y", "Z"];
+  let normals = (0..pixels)
+    .map(|i| Vec3::from_slice(&aov(NORMAL_LAYER)[i * 4..]).normalize_or_zero())
+    .collect::<Vec<_>>();
+  let layers = vec![
+    layer(
+      None,
+      channels(&["R", "G", "B", "A"], &|c| {
+        image.pixels().map(|px| px.0[c]).collect()
+      }),
+    ),
+    layer(
+      Some("albedo"),
+      channels(&rgb, &|c| average(aov(ALBEDO_LAYER), c)),
+    ),
+    layer(
+      Some("normal"),
+      channels(&xyz, &|c| normals.iter().map(|n| n[c]).collect()),
+    ),
+    layer(
+      Some("depth"),
+      channels(&["Z"], &|_| average(aov(NORMAL_LAYER), 3)),
+    ),
+    layer(
+      Some("position"),
+      channels(&xyz, &|c| average(aov(POSITION_LAYER), c)),
+    ),
+    // not averaged, it's the material of the first sample
+    layer(
+      Some("material"),
+      channels(&["id"], &|_| {
+        aov(POSITION_LAYER).chunks(4).map(|px| px[3]).collect()
+      }),
+    ),
+    layer(
+      Some("direct"),
+      channels(&rgb, &|c| average(aov(DIRECT_LAYER), c)),
+    ),
+    layer(
+      Some("indirect"),
+      channels(&rgb, &|c| average(aov(INDIRECT_LAYER), c)),
+    ),
+    layer(
+      Some("emission"),
+      channels(&rgb, &|c| average(aov(EMISSION_LAYER), c)),
+    ),
+  ];
+  let bounds = IntegerBounds::from_dimensions((size.x as usize, size.y as usize));
+  Image::from_layers(ImageAttributes::new(bounds), layers)
+    .write()
+    .to_file(path)?;
+  Ok(())
+}
@@ -0,0 +1,234 @@
+use std::collections::HashSet;
+use winit::event::{ElementState, Event, MouseButton, MouseScrollDelta, WindowEvent};
+use winit::keyboard::{KeyCode, PhysicalKey};
+use glam::{Vec2, Vec3};
+use shared::{Consts, Projection};
+
+const SPEED: f32 = 3.0;
+const SENSITIVITY: f32 = 0.003;
+const ZOOM: f32 = 0.9;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Mode {
+  /// WASD/QE movement with right-drag mouse look.
+  Fly,
+  /// Right-drag turns around `target`, the wheel zooms in and out.
+  Orbit,
+}
+
+pub struct Camera {
+  pub pos: Vec3,
+  pub yaw: f32,
+  pub pitch: f32,
+  /// Vertical half-angle of the view in radians.
+  pub fov: f32,
+  /// Radius of the lens, zero disables depth of field.
+  pub aperture: f32,
+  /// Distance to the plane in focus.
+  pub focus: f32,
+  pub projection: Projection,
+  /// Half the height of the view with the orthographic projection.
+  pub ortho_size: f32,
+  /// Number of aperture blades, fewer than three give a round aperture.
+  pub blades: u32,
+  pub blade_rotation: f32,
+  /// Pose at the end of the frame for motion blur, unset parts stay where
+  /// they are at the start. Moving the camera by hand carries it along.
+  pub pos_end: Option<Vec3>,
+  pub yaw_end: Option<f32>,
+  pub pitch_end: Option<f32>,
+  pub mode: Mode,
+  pub target: Vec3,
+  pub distance: f32,
+  keys: HashSet<KeyCode>,
+  looking: bool,
+  cursor: Option<Vec2>,
+  changed: bool,
+}
+
+impl Camera {
+  pub fn new(pos: Vec3) -> Self {
+    Self {
+      pos,
+      yaw: 0.0,
+      pitch: 0.0,
+      fov: 0.6,
+      aperture: 0.05,
+      focus: 5.0,
+      projection: Projection::Perspective,
+      ortho_size: 3.0,
+      blades: 0,
+      blade_rotation: 0.0,
+      pos_end: None,
+      yaw_end: None,
+      pitch_end: None,
+      mode: Mode::Fly,
+      target: pos - 5.0 * Vec3::Z,
+      distance: 5.0,
+      keys: HashSet::new(),
+      looking: false,
+      cursor: None,
+      changed: false,
+    }
+  }
+
+  /// Direction the camera looks in. Zero yaw and pitch look down -Z.
+  pub fn forward(&self) -> Vec3 {
+    Vec3::new(
+      -self.yaw.sin() * self.pitch.cos(),
+      self.pitch.sin(),
+      -self.yaw.cos() * self.pitch.cos(),
+    )
+  }
+
+  pub fn right(&self) -> Vec3 {
+    Vec3::new(self.yaw.cos(), 0.0, -self.yaw.sin())
+  }
+
+  /// Switches between flying and orbiting without moving the view. Orbiting
+  /// picks up the point `distance` in front of the camera as its target.
+  pub fn set_mode(&mut self, mode: Mode) {
+    if mode == Mode::Orbit && self.mode == Mode::Fly {
+      self.target = self.pos + self.forward() * self.distance;
+    }
+    self.mode = mode;
+  }
+
+  pub fn toggle_mode(&mut self) {
+    self.set_mode(match self.mode {
+      Mode::Fly => Mode::Orbit,
+      Mode::Orbit => Mode::Fly,
+    });
+  }
+
+  /// Keeps the current direction and moves back until the box from `min` to
+  /// `max` fits in the view.
+  pub fn frame(&mut self, min: Vec3, max: Vec3) {
+    self.target = (min + max) / 2.0;
+    self.distance = (max - min).length() / 2.0 / self.fov.sin();
+    self.move_to(
+      self.target - self.forward() * self.distance,
+      self.yaw,
+      self.pitch,
+    );
+    self.changed = true;
+  }
+
+  /// Moves the start of the frame to a new pose and the end by as much, so
+  /// motion blur keeps the same motion.
+  fn move_to(&mut self, pos: Vec3, yaw: f32, pitch: f32) {
+    self.pos_end = self.pos_end.map(|end| end + pos - self.pos);
+    self.yaw_end = self.yaw_end.map(|end| end + yaw - self.yaw);
+    self.pitch_end = self.pitch_end.map(|end| end + pitch - self.pitch);
+    self.pos = pos;
+    self.yaw = yaw;
+    self.pitch = pitch;
+  }
+
+  /// Restarts accumulation on the next update, for changes made from outside
+  /// like the lens sliders.
+  pub fn mark_changed(&mut self) {
+    self.changed = true;
+  }
+
+  pub fn write(&self, consts: &mut Consts) {
+    consts.cam_pos = self.pos;
+    consts.cam_yaw = self.yaw;
+    consts.cam_pitch = self.pitch;
+    consts.cam_pos_end = self.pos_end.unwrap_or(self.pos);
+    consts.cam_yaw_end = self.yaw_end.unwrap_or(self.yaw);
+    consts.cam_pitch_end = self.pitch_end.unwrap_or(self.pitch);
+    consts.cam_fov = self.fov;
+    consts.cam_aperture = self.aperture;
+    consts.cam_focus = self.focus;
+    consts.cam_projection = self.projection as u32;
+    consts.cam_ortho_size = self.ortho_size;
+    consts.cam_blades = self.blades;
+    consts.cam_blade_rotation = self.blade_rotation;
+  }
+
+  pub fn handle_event<T>(&mut self, event: &Event<T>) {
+    let Event::WindowEvent { event, .. } = event else {
+      return;
+    };
+    match event {
+      WindowEvent::KeyboardInput { event, .. } => {
+        if let PhysicalKey::Code(key) = event.physical_key {
+          match event.state {
+            ElementState::Pressed => self.keys.insert(key),
+            ElementState::Released => self.keys.remove(&key),
+          };
+          if event.state == ElementState::Pressed && !event.repeat && key == KeyCode::Tab {
+            self.toggle_mode();
+          }
+        }
+      }
+      WindowEvent::MouseInput {
+        button: MouseButton::Right,
+        state,
+        ..
+      } => self.looking = *state == ElementState::Pressed,
+      WindowEvent::CursorMoved { position, .. } => {
+        let pos = Vec2::new(position.x as _, position.y as _);
+        if let (true, Some(last)) = (self.looking, self.cursor) {
+          let delta = (pos - last) * SENSITIVITY;
+          let pitch = (self.pitch - delta.y).clamp(-1.55, 1.55);
+          self.move_to(self.pos, self.yaw - delta.x, pitch);
+          self.changed = true;
+        }
+        self.cursor = Some(pos);
+      }
+      WindowEvent::MouseWheel { delta, .. } if self.mode == Mode::Orbit => {
+        let lines = match delta {
+          MouseScrollDelta::LineDelta(_, y) => *y,
+          MouseScrollDelta::PixelDelta(p) => p.y as f32 / 40.0,
+        };
+        self.distance *= ZOOM.powf(lines);
+        self.changed = true;
+      }
+      WindowEvent::Focused(false) => {
+        self.keys.clear();
+        self.looking = false;
+      }
+      _ => {}
+    }
+  }
+
+  /// Moves the camera by the held keys over `dt` seconds. Returns whether the
+  /// view changed since the last update.
+  pub fn update(&mut self, dt: f32) -> bool {
+    match self.mode {
+      Mode::Fly => {
+        let mut dir = Vec3::ZERO;
+        for (key, axis) in [
+          (KeyCode::KeyW, self.forward()),
+          (KeyCode::KeyS, -self.forward()),
+          (KeyCode::KeyD, self.right()),
+          (KeyCode::KeyA, -self.right()),
+          (KeyCode::KeyE, Vec3::Y),
+          (KeyCode::KeyQ, -Vec3::Y),
+        ] {
+          if self.keys.contains(&key) {
+            dir += axis;
+          }
+        }
+        if dir != Vec3::ZERO {
+          self.move_to(
+            self.pos + dir.normalize() * SPEED * dt,
+            self.yaw,
+            self.pitch,
+          );
+          self.changed = true;
+        }
+      }
+      Mode::Orbit => {
+        self.move_to(
+          self.target - self.forward() * self.distance,
+          self.yaw,
+          self.pitch,
+        );
+      }
+    }
+    std::mem::take(&mut self.changed)
+  }
+}
@@ -0,0 +1,202 @@
+use std::fs;
+use std::io::ErrorKind;
+use glam::{UVec2, Vec2};
+use crate::renderer::Renderer;
+use crate::scene::Scene;
+use crate::Result;
+
+const MAGIC: &[u8; 4] = b"uckp";
+const HEADER: usize = 32;
+/// Starting value of `hash`.
+pub const HASH_START: u64 = 0xcbf29ce484222325;
+
+/// Continues an FNV-1a hash over `bytes`, taking eight at a time to get through
+/// textures quickly.
+pub fn hash(hash: u64, bytes: &[u8]) -> u64 {
+  let step = |hash: u64, v: u64| (hash ^ v).wrapping_mul(0x100000001b3);
+  let words = bytes.chunks_exact(8);
+  let rest = words.remainder();
+  let hash = words.fold(hash, |hash, w| {
+    step(hash, u64::from_le_bytes(w.try_into().unwrap()))
+  });
+  rest.iter().fold(hash, |hash, &b| step(hash, b as u64))
+}
+
+/// Hash of everything the accumulated image depends on apart from the random
+/// numbers, so only a render of the same view is resumed. Covers the loaded
+/// files through `Renderer::assets`, and the settings through their `Debug`
+/// output, which unlike the raw structs has no padding in it.
+fn fingerprint(renderer: &Renderer, scene: &Scene) -> u64 {
+  let mut consts = renderer.consts;
+  consts.samples = 0;
+  consts.rand = 0;
+  consts.batch = 0;
+  consts.noise = 0.0;
+  consts.aov = 0;
+  consts.probe = Vec2::ZERO;
+  consts.window = Vec2::ZERO;
+  consts.view_offset = Vec2::ZERO;
+  consts.view_scale = 0.0;
+  consts.tile_offset = Vec2::ZERO;
+  consts.tile_size = Vec2::ZERO;
+  let text = format!(
+    "{:?} {:?} {:?} {:?}",
+    consts, scene.spheres, scene.mesh, scene.materials
+  );
+  hash(renderer.assets, text.as_bytes())
+}
+
+/// What a checkpoint starts with, ahead of the texture data.
+#[derive(Debug, PartialEq)]
+struct Header {
+  /// `fingerprint` of the render it was saved from.
+  hash: u64,
+  size: UVec2,
+  tile: u32,
+  /// Samples per pixel accumulated, and the seed of the next one.
+  samples: u32,
+  rand: u32,
+}
+
+impl Header {
+  fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(self.hash.to_le_bytes());
+    for v in [self.size.x, self.size.y, self.tile, self.samples, self.rand] {
+      bytes.extend(v.to_le_bytes());
+    }
+    bytes
+  }
+
+  /// Reads the header at the start of `bytes`, if they are a checkpoint.
+  fn from_bytes(bytes: &[u8]) -> Option<Self> {
+    if bytes.len() < HEADER || &bytes[..4] != MAGIC {
+      return None;
+    }
+    let word = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+    Some(Self {
+      hash: u64::from_le_bytes(bytes[4..12].try_into().unwrap()),
+      size: UVec2::new(word(12), word(16)),
+      tile: word(20),
+      samples: word(24),
+      rand: word(28),
+    })
+  }
+}
+
+/// Textures saved in a checkpoint: the accumulators of the current tile, and
+/// the finished tiles in `Renderer::image` if it's kept.
+fn textures(renderer: &Renderer) -> Vec<&wgpu::Texture> {
+  let mut textures = renderer.textures.accumulators().to_vec();
+  textures.extend(renderer.image.as_ref().map(|(image, _)| image));
+  textures
+}
+
+/// Writes everything accumulated for the samples, the tile, the sample count
+/// and the seed of the next sample to `path`, all little-endian. The file is
+/// replaced at once, so a crash while writing keeps the previous checkpoint.
+pub fn save(renderer: &Renderer, scene: &Scene, path: &str) -> Result {
+  let mut bytes = Header {
+    hash: fingerprint(renderer, scene),
+    size: renderer.consts.size.as_uvec2(),
+    tile: renderer.tile() as u32,
+    samples: renderer.samples(),
+    rand: renderer.consts.rand,
+  }
+  .to_bytes();
+  for texture in textures(renderer) {
+    for v in renderer.read_texture(texture) {
+      bytes.extend(v.to_le_bytes());
+    }
+  }
+  let tmp = format!("{}.tmp", path);
+  fs::write(&tmp, bytes)?;
+  fs::rename(tmp, path)?;
+  Ok(())
+}
+
+/// Restores the checkpoint at `path` if it was saved for the current view,
+/// moving on to the tile it was saved in. Returns whether it was, and `false`
+/// if there is no such file. Without `Renderer::image` the checkpoint has no
+/// finished tiles, and the ones before the saved tile have to be rendered
+/// again.
+pub fn resume(renderer: &mut Renderer, scene: &Scene, path: &str) -> Result<bool> {
+  let bytes = match fs::read(path) {
+    Ok(bytes) => bytes,
+    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+    Err(e) => return Err(e.into()),
+  };
+  let Some(header) = Header::from_bytes(&bytes) else {
+    return Err(format!("{} is not a checkpoint", path).into());
+  };
+  let tile = header.tile as usize;
+  if header.hash != fingerprint(renderer, scene) || tile >= renderer.tiles().len() {
+    return Ok(false);
+  }
+  renderer.set_tile(tile);
+  let data = bytes[HEADER..]
+    .chunks_exact(4)
+    .map(|v| f32::from_le_bytes(v.try_into().unwrap()))
+    .collect::<Vec<_>>();
+  let textures = textures(renderer);
+  let floats = |t: &wgpu::Texture| {
+    let texels = t.width() * t.height() * t.depth_or_array_layers();
+    (texels * t.format().block_size(None).unwrap() / 4) as usize
+  };
+  if data.len() != textures.iter().map(|t| floats(t)).sum::<usize>() {
+    return Err(format!("{} is truncated", path).into());
+  }
+  let mut rest = &data[..];
+  for texture in textures {
+    let (layer, next) = rest.split_at(floats(texture));
+    renderer.write_texture(texture, layer);
+    rest = next;
+  }
+  renderer.consts.samples = header.samples + 1;
+  renderer.consts.rand = header.rand;
+  Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn hash_matches_fnv1a_on_bytes_shorter_than_a_word() {
+    assert_eq!(hash(HASH_START, b""), HASH_START);
+    assert_eq!(hash(HASH_START, b"a"), 0xaf63dc4c8601ec8c);
+  }
+
+  #[test]
+  fn hash_covers_every_byte() {
+    let bytes = [7u8; 19];
+    let whole = hash(HASH_START, &bytes);
+    for i in 0..bytes.len() {
+      let mut changed = bytes;
+      changed[i] ^= 1;
+      assert_ne!(hash(HASH_START, &changed), whole, "byte {}", i);
+    }
+    assert_ne!(hash(HASH_START, &bytes[..18]), whole);
+  }
+
+  #[test]
+  fn header_round_trips() {
+    let header = Header {
+      hash: 0x0123456789abcdef,
+      size: UVec2::new(1920, 1080),
+      tile: 3,
+      samples: 250,
+      rand: 0xdeadbeef,
+    };
+    let bytes = header.to_bytes();
+    assert_eq!(bytes.len(), HEADER);
+    assert_eq!(bytes[20..24], [3, 0, 0, 0]);
+    assert_eq!(Header::from_bytes(&bytes), Some(header));
+  }
+
+  #[test]
+  fn header_rejects_other_files() {
+    assert_eq!(Header::from_bytes(b"uckp"), None);
+    assert_eq!(Header::from_bytes(&[0; HEADER]), None);
+  }
+}
@@ -0,0 +1,187 @@
+use glam::UVec2;
+use wgpu::util::DeviceExt;
+use shared::DenoisePass;
+use crate::cast;
+use crate::renderer::{layout, WORKGROUP};
+
+/// Passes of the wavelet filter, each with twice the step of the last.
+const PASSES: u32 = 5;
+
+/// Filters the noise out of the accumulated samples, guided by the albedo,
+/// normals and distances the camera rays hit first, from the AOVs. Meant for
+/// judging a preview after a few samples, the sums stay as they are.
+pub struct Denoiser {
+  prepare: wgpu::ComputePipeline,
+  filter: wgpu::ComputePipeline,
+  guides: wgpu::BindGroup,
+  /// Input and output of `prepare` and then of each pass of `filter`.
+  passes: Vec<wgpu::BindGroup>,
+  /// The filtered image, sums with the sample count in alpha like
+  /// `Textures::bind_group`.
+  pub bind_group: wgpu::BindGroup,
+}
+
+impl Denoiser {
+  /// Sets up filtering a canvas of `canvas` pixels. `sources` are the sums,
+  /// moments and AOVs written by the path tracer. `uniform` is the layout of
+  /// the consts, and `texture` that of a sampled texture to show the result
+  /// with.
+  pub fn new(
+    device: &wgpu::Device,
+    shader: &wgpu::ShaderModule,
+    uniform: &wgpu::BindGroupLayout,
+    texture: &wgpu::BindGroupLayout,
+    sources: [&wgpu::Texture; 3],
+    canvas: UVec2,
+  ) -> Self {
+    let unfilterable = wgpu::BindingType::Texture {
+      sample_type: wgpu::TextureSampleType::Float { filterable: false },
+      view_dimension: wgpu::TextureViewDimension::D2,
+      multisampled: false,
+    };
+    let unfilterable_array = wgpu::BindingType::Texture {
+      sample_type: wgpu::TextureSampleType::Float { filterable: false },
+      view_dimension: wgpu::TextureViewDimension::D2Array,
+      multisampled: false,
+    };
+    let guides_layout = layout(
+      device,
+      wgpu::ShaderStages::COMPUTE,
+      &[unfilterable, unfilterable, unfilterable_array],
+    );
+    let pass_layout = layout(
+      device,
+      wgpu::ShaderStages::COMPUTE,
+      &[
+        wgpu::BindingType::Buffer {
+          ty: wgpu::BufferBindingType::Uniform,
+          has_dynamic_offset: false,
+          min_binding_size: None,
+        },
+        unfilterable,
+        wgpu::BindingType::StorageTexture {
+          access: wgpu::StorageTextureAccess::WriteOnly,
+          format: wgpu::TextureFormat::Rgba32Float,
+          view_dimension: wgpu::TextureViewDimension::D2,
+        },
+      ],
+    );
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+      bind_group_layouts: &[uniform, &guides_layout, &pass_layout],
+      push_constant_ranges: &[],
+      label: None,
+    });
+    let pipeline = |entry_point| {
+      device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        layout: Some(&pipeline_layout),
+        module: shader,
+        entry_point,
+        label: None,
+      })
+    };
+
+    let views = sources.map(|t| t.create_view(&wgpu::TextureViewDescriptor::default()));
+    let guides = device.create_bind_group(&wgpu::BindGroupDescriptor {
+      layout: &guides_layout,
+      entries: &[0, 1, 2].map(|i| wgpu::BindGroupEntry {
+        binding: i,
+        resource: wgpu::BindingResource::TextureView(&views[i as usize]),
+      }),
+      label: None,
+    });
+
+    // ping-pong between two textures, and write the last pass to a third
+    let [ping, pong, out] = [(); 3].map(|_| {
+      device
+        .create_texture(&wgpu::TextureDescriptor {
+          size: wgpu::Extent3d {
+            width: canvas.x,
+            height: canvas.y,
+            depth_or_array_layers: 1,
+          },
+          mip_level_count: 1,
+          sample_count: 1,
+          dimension: wgpu::TextureDimension::D2,
+          format: wgpu::TextureFormat::Rgba32Float,
+          usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING,
+          view_formats: &[],
+          label: None,
+        })
+        .create_view(&wgpu::TextureViewDescriptor::default())
+    });
+    let pass = |step, input, output| {
+      let settings = DenoisePass {
+        step,
+        last: (step == 1 << (PASSES - 1)) as u32,
+        padding: [0; 2],
+      };
+      let buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        contents: cast(&settings),
+        usage: wgpu::BufferUsages::UNIFORM,
+        label: None,
+      });
+      device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: &pass_layout,
+        entries: &[
+          wgpu::BindGroupEntry {
+            binding: 0,
+            resource: buf.as_entire_binding(),
+          },
+          wgpu::BindGroupEntry {
+            binding: 1,
+            resource: wgpu::BindingResource::TextureView(input),
+          },
+          wgpu::BindGroupEntry {
+            binding: 2,
+            resource: wgpu::BindingResource::TextureView(output),
+          },
+        ],
+        label: None,
+      })
+    };
+    // `prepare` ignores its input
+    let mut passes = vec![pass(0, &pong, &ping)];
+    for i in 0..PASSES {
+      let (input, output) = match i % 2 {
+        0 => (&ping, &pong),
+        _ => (&pong, &ping),
+      };
+      let output = if i == PASSES - 1 { &out } else { output };
+      passes.push(pass(1 << i, input, output));
+    }
+
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+      layout: texture,
+      entries: &[wgpu::BindGroupEntry {
+        binding: 0,
+        resource: wgpu::BindingResource::TextureView(&out),
+      }],
+      label: None,
+    });
+    Self {
+      prepare: pipeline("dn_prepare"),
+      filter: pipeline("dn_filter"),
+      guides,
+      passes,
+      bind_group,
+    }
+  }
+
+  /// Records filtering a tile of `size` into `bind_group`.
+  pub fn denoise(
+    &self,
+    encoder: &mut wgpu::CommandEncoder,
+    uniform_bind_group: &wgpu::BindGroup,
+    size: UVec2,
+  ) {
+    let groups = (size + WORKGROUP - 1) / WORKGROUP;
+    for (i, pass_bind_group) in self.passes.iter().enumerate() {
+      let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+      pass.set_pipeline(if i == 0 { &self.prepare } else { &self.filter });
+      pass.set_bind_group(0, uniform_bind_group, &[]);
+      pass.set_bind_group(1, &self.guides, &[]);
+      pass.set_bind_group(2, pass_bind_group, &[]);
+      pass.dispatch_workgroups(groups.x, groups.y, 1);
+    }
+  }
+}
@@ -1,21 +1,40 @@
+mod aov;
+mod camera;
+mod checkpoint;
+mod denoise;
+mod progress;
+mod renderer;
+mod scene;
 mod ui;
+mod view;
+mod wavefront;
 
 use std::{mem, slice};
-use std::io::BufReader;
-use std::fs::File;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::f32::consts::PI;
 use winit::window::WindowBuilder;
 use winit::event_loop::EventLoop;
-use winit::event::{Event, WindowEvent, MouseButton, ElementState};
+use winit::event::{Event, WindowEvent, MouseButton, ElementState, KeyEvent};
+use winit::keyboard::{KeyCode, PhysicalKey};
 use wgpu::util::DeviceExt;
 use log::LevelFilter;
-use glam::{Vec2, Vec3};
-use obj::{load_obj, Obj};
-use shared::{Consts, Vertex, Material};
+use glam::{UVec2, Vec2};
+use shared::{Aov, Vertex, Projection, AOV_LAYERS};
+use crate::camera::Mode;
+use crate::progress::{Command, Limits, Progress};
+use crate::renderer::Renderer;
+use crate::scene::Scene;
 use crate::ui::Context;
+use crate::view::View;
 
 type Result<T = ()> = std::result::Result<T, Box<dyn std::error::Error>>;
 
+/// Samples per pixel in the window when `--samples` isn't given.
 const SAMPLES: u32 = 4096;
+/// Samples per pixel without a window, or with tiles.
+const TILE_SAMPLES: u32 = 256;
+/// Most samples per pixel in one dispatch, to stay clear of GPU timeouts.
+const MAX_BATCH: u32 = 64;
 
 fn main() -> Result {
   env_logger::builder()
@@ -24,18 +43,150 @@ fn main() -> Result {
     .filter(Some("wgpu_hal"), LevelFilter::Warn)
     .init();
   std::panic::set_hook(Box::new(|i| log::error!("{}", i)));
-  let event_loop = EventLoop::new()?;
-  let window = WindowBuilder::new().build(&event_loop)?;
+  let opts = Options::parse()?;
+  let scene = Scene::load(&opts.scene)?;
+  if opts.render || opts.sequence {
+    render_headless(scene, &opts)
+  } else {
+    run_window(scene, &opts)
+  }
+}
 
-  let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
-  let surface = unsafe { instance.create_surface(&window)? };
-  let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
-    power_preference: wgpu::PowerPreference::HighPerformance,
-    compatible_surface: Some(&surface),
-    force_fallback_adapter: false,
-  }))
-  .unwrap();
-  let (device, queue) = pollster::block_on(adapter.request_device(
+/// Command line options: `[--scene path] [--render | --sequence] [--fallback]
+/// [--size WxH] [--tile n] [--samples n] [--time secs] [--error x]
+/// [--out path] [--depth 8|16] [--checkpoint path] [--checkpoint-every n]
+/// [--resume] [--wavefront] [--batch n] [--frame-time ms] [--noise x]`.
+///
+/// Sampling stops at the first of the sample, time and error limits. Without
+/// a window, lines of `pause`, `resume`, `continue` past the limits and `stop`
+/// on standard input steer it.
+struct Options {
+  scene: String,
+  /// Render the first frame without a window, write it out and exit.
+  render: bool,
+  /// Like `render`, but for every frame of the animation.
+  sequence: bool,
+  /// Render on a software adapter like lavapipe, for machines without a GPU.
+  fallback: bool,
+  /// Resolution of the render, which the window shows letterboxed.
+  size: UVec2,
+  /// Render in square tiles of this size, to keep each pass short and to go
+  /// past the largest texture size without a window.
+  tile: Option<u32>,
+  /// Samples per pixel for each frame, and for each tile in it. Defaults to
+  /// `SAMPLES` in the window and `TILE_SAMPLES` without one or with tiles.
+  samples: Option<u32>,
+  /// Seconds of sampling for each frame, split evenly between the tiles.
+  time: Option<f32>,
+  /// Mean relative error of the pixels to stop at, as estimated from the
+  /// variance of their samples.
+  error: Option<f32>,
+  /// Where to write the rendered frames, with the run of `#`s replaced by the
+  /// frame number. The extension picks linear OpenEXR or a tonemapped image.
+  /// OpenEXR files get the AOVs as more layers: albedo, normal, depth,
+  /// position, material, direct, indirect and emission.
+  out: Option<String>,
+  /// Bits per channel of tonemapped images.
+  depth: u32,
+  /// Where the accumulated samples are saved on exit.
+  checkpoint: String,
+  /// Also save a checkpoint after every this many samples, unless zero.
+  checkpoint_every: u32,
+  /// Start from the samples in `checkpoint`, if it was saved for the same
+  /// view and files.
+  resume: bool,
+  /// Trace with the wavefront integrator rather than the megakernel.
+  wavefront: bool,
+  /// Samples per pixel in each dispatch, fewer with the wavefront integrator.
+  /// Unset, it's fitted to `frame_time`.
+  batch: Option<u32>,
+  /// Seconds each dispatch should take when `batch` is unset, which keeps the
+  /// window responsive while making full use of the GPU.
+  frame_time: f32,
+  /// Relative noise below which a pixel stops getting samples, which then go to
+  /// the noisy ones: the sample limit counts the mean taken per pixel. Off by
+  /// default, since a pixel's variance can be low only because its rare light
+  /// paths haven't turned up yet.
+  noise: f32,
+}
+
+impl Options {
+  fn parse() -> Result<Self> {
+    let mut opts = Self {
+      scene: "scene.txt".into(),
+      render: false,
+      sequence: false,
+      fallback: false,
+      size: UVec2::new(1280, 720),
+      tile: None,
+      samples: None,
+      time: None,
+      error: None,
+      out: None,
+      depth: 8,
+      checkpoint: "checkpoint.bin".into(),
+      checkpoint_every: 0,
+      resume: false,
+      wavefront: false,
+      batch: None,
+      frame_time: 1.0 / 30.0,
+      noise: 0.0,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+      let mut value = || {
+        args
+          .next()
+          .ok_or_else(|| format!("missing value for {}", arg))
+      };
+      match arg.as_str() {
+        "--scene" => opts.scene = value()?,
+        "--render" => opts.render = true,
+        "--sequence" => opts.sequence = true,
+        "--fallback" => opts.fallback = true,
+        "--size" => {
+          let size = value()?;
+          let (w, h) = size
+            .split_once('x')
+            .ok_or_else(|| format!("invalid size {}", size))?;
+          opts.size = UVec2::new(w.parse()?, h.parse()?);
+        }
+        "--tile" => opts.tile = Some(value()?.parse()?),
+        "--samples" => opts.samples = Some(value()?.parse()?),
+        "--time" => opts.time = Some(value()?.parse()?),
+        "--error" => opts.error = Some(value()?.parse()?),
+        "--out" => opts.out = Some(value()?),
+        "--depth" => {
+          opts.depth = value()?.parse()?;
+          if !matches!(opts.depth, 8 | 16) {
+            return Err(format!("can't write {} bits per channel", opts.depth).into());
+          }
+        }
+        "--checkpoint" => opts.checkpoint = value()?,
+        "--checkpoint-every" => opts.checkpoint_every = value()?.parse()?,
+        "--resume" => opts.resume = true,
+        "--wavefront" => opts.wavefront = true,
+        "--batch" => opts.batch = Some(value()?.parse::<u32>()?.clamp(1, MAX_BATCH)),
+        "--frame-time" => opts.frame_time = value()?.parse::<f32>()? / 1000.0,
+        "--noise" => opts.noise = value()?.parse::<f32>()?.max(0.0),
+        _ => return Err(format!("unknown option {}", arg).into()),
+      }
+    }
+    Ok(opts)
+  }
+
+  /// Limits of each of `tiles` tiles, with `samples` unless given.
+  fn limits(&self, samples: u32, tiles: usize) -> Limits {
+    Limits {
+      samples: self.samples.unwrap_or(samples),
+      time: self.time.map(|t| t / tiles as f32),
+      error: self.error,
+    }
+  }
+}
+
+fn request_device(adapter: &wgpu::Adapter) -> Result<(wgpu::Device, wgpu::Queue)> {
+  Ok(pollster::block_on(adapter.request_device(
     &wgpu::DeviceDescriptor {
       features: wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES,
       limits: wgpu::Limits {
@@ -45,189 +196,353 @@ fn main() -> Result {
       label: None,
     },
     None,
-  ))?;
-
-  let shader = device.create_shader_module(wgpu::include_spirv!(env!("shaders.spv")));
-  let rt_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-    layout: None,
-    vertex: wgpu::VertexState {
-      module: &shader,
-      entry_point: "quad_v",
-      buffers: &[],
-    },
-    fragment: Some(wgpu::FragmentState {
-      module: &shader,
-      entry_point: "main_f",
-      targets: &[Some(wgpu::ColorTargetState {
-        format: wgpu::TextureFormat::Rgba32Float,
-        blend: None,
-        write_mask: wgpu::ColorWrites::ALL,
-      })],
-    }),
-    primitive: wgpu::PrimitiveState::default(),
-    depth_stencil: None,
-    multisample: wgpu::MultisampleState::default(),
-    multiview: None,
-    label: None,
-  });
-  let quad_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-    layout: None,
-    vertex: wgpu::VertexState {
-      module: &shader,
-      entry_point: "quad_v",
-      buffers: &[],
-    },
-    fragment: Some(wgpu::FragmentState {
-      module: &shader,
-      entry_point: "quad_f",
-      targets: &[Some(wgpu::ColorTargetState {
-        format: wgpu::TextureFormat::Bgra8Unorm,
-        blend: None,
-        write_mask: wgpu::ColorWrites::ALL,
-      })],
-    }),
-    primitive: wgpu::PrimitiveState::default(),
-    depth_stencil: None,
-    multisample: wgpu::MultisampleState::default(),
-    multiview: None,
-    label: None,
-  });
-  let ui_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-    layout: None,
-    vertex: wgpu::VertexState {
-      module: &shader,
-      entry_point: "ui_v",
-      buffers: &[wgpu::VertexBufferLayout {
-        array_stride: mem::size_of::<Vertex>() as _,
-        step_mode: wgpu::VertexStepMode::Vertex,
-        attributes: &wgpu::vertex_attr_array![0 => Float32x2, 1 => Float32x2, 2 => Float32x3],
-      }],
-    },
-    fragment: Some(wgpu::FragmentState {
-      module: &shader,
-      entry_point: "ui_f",
-      targets: &[Some(wgpu::ColorTargetState {
-        format: wgpu::TextureFormat::Bgra8Unorm,
-        blend: Some(wgpu::BlendState::ALPHA_BLENDING),
-        write_mask: wgpu::ColorWrites::ALL,
-      })],
-    }),
-    primitive: wgpu::PrimitiveState::default(),
-    depth_stencil: None,
-    multisample: wgpu::MultisampleState::default(),
-    multiview: None,
-    label: None,
-  });
-  let tex_layout = rt_pipeline.get_bind_group_layout(2);
+  ))?)
+}
 
-  let uniform_buf = device.create_buffer(&wgpu::BufferDescriptor {
-    size: mem::size_of::<Consts>() as _,
-    usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-    mapped_at_creation: false,
-    label: None,
-  });
-  let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-    layout: &rt_pipeline.get_bind_group_layout(0),
-    entries: &[wgpu::BindGroupEntry {
-      binding: 0,
-      resource: uniform_buf.as_entire_binding(),
-    }],
-    label: None,
-  });
+/// Renders the first frame, or each frame of the animation for `--sequence`,
+/// until it reaches the limits and writes it out, without opening a window.
+fn render_headless(mut scene: Scene, opts: &Options) -> Result {
+  let (first, last) = match opts.sequence {
+    true => scene.frames,
+    false => (scene.frames.0, scene.frames.0),
+  };
+  let out = match (&opts.out, opts.sequence) {
+    (Some(out), _) => out.as_str(),
+    (None, true) => "frame_####.exr",
+    (None, false) => "render.exr",
+  };
+  if last > first && !out.contains('#') {
+    return Err(format!("{} has no `#` for the frame number", out).into());
+  }
+  let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+  let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+    power_preference: wgpu::PowerPreference::HighPerformance,
+    compatible_surface: None,
+    force_fallback_adapter: opts.fallback,
+  }))
+  .ok_or(match opts.fallback {
+    true => "no software adapter found",
+    false => "no adapter found, --fallback renders in software",
+  })?;
+  let info = adapter.get_info();
+  log::info!("rendering on {} ({:?})", info.name, info.backend);
+  let (device, queue) = request_device(&adapter)?;
+  let (width, height) = (opts.size.x, opts.size.y);
+  let mut renderer = Renderer::new(
+    device,
+    queue,
+    &mut scene,
+    width,
+    height,
+    opts.tile,
+    opts.wavefront,
+  )?;
+  renderer.consts.noise = opts.noise;
+  let tiles = renderer.tiles().len();
+  let limits = opts.limits(TILE_SAMPLES, tiles);
+  let commands = progress::commands();
+  let mut batch = opts.batch.unwrap_or(1);
+  for frame in first..=last {
+    let start = Instant::now();
+    scene.animate(frame as f32);
+    renderer.set_scene(&scene);
+    let mut image = vec![0.0; (width * height * 4) as usize];
+    let mut aovs = vec![0.0; (width * height * 4 * AOV_LAYERS) as usize];
+    let mut limit = "";
+    let resumed = opts.resume && checkpoint::resume(&mut renderer, &scene, &opts.checkpoint)?;
+    // the resumed tile goes first, the ones before it are rendered after the rest
+    let first_tile = match resumed {
+      true => renderer.tile(),
+      false => 0,
+    };
+    if resumed {
+      log::info!(
+        "resuming tile {} from {} samples",
+        first_tile + 1,
+        renderer.samples()
+      );
+    }
+    for i in 0..tiles {
+      let tile = (first_tile + i) % tiles;
+      if i > 0 || !resumed {
+        renderer.set_tile(tile);
+      }
+      let mut progress = Progress::new(limits);
+      loop {
+        progress.poll(&commands);
+        if let Some(reached) = progress.reached(renderer.samples()) {
+          limit = reached;
+          break;
+        }
+        let remaining = progress.remaining(renderer.samples());
+        renderer.consts.batch = batch.min(remaining).min(renderer.max_batch());
+        let dispatched = Instant::now();
+        let mut encoder = renderer
+          .device
+          .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        renderer.write_consts();
+        renderer.sample(&mut encoder);
+        renderer.queue.submit([encoder.finish()]);
+        renderer.device.poll(wgpu::Maintain::Wait);
+        renderer.advance();
+        let elapsed = dispatched.elapsed().as_secs_f32();
+        progress.add_time(elapsed);
+        if opts.batch.is_none() {
+          batch = fit_batch(renderer.consts.batch, elapsed, opts.frame_time);
+        }
+        if progress.needs_estimate() {
+          progress.set_error(renderer.error());
+        }
+        if progress.needs_taken(renderer.samples()) {
+          progress.set_taken(renderer.taken());
+        }
+        let every = opts.checkpoint_every;
+        if every > 0 && crossed(renderer.samples(), renderer.consts.batch, every) {
+          checkpoint::save(&renderer, &scene, &opts.checkpoint)?;
+        }
+      }
+      renderer.read_tile(&mut image, &mut aovs);
+      if tiles > 1 {
+        log::info!("tile {}/{}", tile + 1, tiles);
+      }
+      // a stop ends the render, leaving the tiles after this one black
+      if limit == "stop" {
+        break;
+      }
+    }
+    let path = frame_path(out, frame);
+    aov::save(&image, Some(&aovs), opts.size, &path, opts.depth)?;
+    log::info!(
+      "frame {}/{}: {} samples in {:.1}s, stopped by {}, to {}",
+      frame - first + 1,
+      last - first + 1,
+      renderer.samples(),
+      start.elapsed().as_secs_f32(),
+      limit,
+      path,
+    );
+    if limit == "stop" {
+      break;
+    }
+  }
+  Ok(())
+}
 
-  let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
-  let sampler_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-    layout: &rt_pipeline.get_bind_group_layout(1),
-    entries: &[wgpu::BindGroupEntry {
-      binding: 0,
-      resource: wgpu::BindingResource::Sampler(&sampler),
-    }],
-    label: None,
-  });
+/// Samples per dispatch to take about `target` seconds, given that `batch` of
+/// them took `elapsed`. Changes at most twofold at a time to ride out noise.
+fn fit_batch(batch: u32, elapsed: f32, target: f32) -> u32 {
+  let scale = (target / elapsed.max(1e-4)).clamp(0.5, 2.0);
+  ((batch as f32 * scale).round() as u32).clamp(1, MAX_BATCH)
+}
 
-  let obj: Obj = load_obj(BufReader::new(File::open("untitled.obj")?))?;
-  let mut min = Vec3::MAX;
-  let mut max = Vec3::MIN;
-  let verts = obj
-    .indices
-    .iter()
-    .map(|i| {
-      let pos = Vec3::from(obj.vertices[*i as usize].position);
-      min = min.min(pos);
-      max = max.max(pos);
-      pos.extend(1.0)
-    })
-    .collect::<Vec<_>>();
-  let vtx_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-    contents: cast_slice(&verts),
-    usage: wgpu::BufferUsages::STORAGE,
-    label: None,
-  });
-  log::info!("{} {} {}", min, max, verts.len());
-  let materials = [
-    (Vec3::splat(0.8), Material::Lambertian),
-    (Vec3::X, Material::Lambertian),
-    (Vec3::Y, Material::Lambertian),
-    (Vec3::Z, Material::Lambertian),
-    (Vec3::new(1.0, 0.0, 1.0), Material::Lambertian),
-    (Vec3::ONE, Material::Dielectric),
-    (Vec3::splat(0.8), Material::Metal),
-    (Vec3::splat(5.0), Material::Emissive),
-  ];
-  let material_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-    contents: cast_slice(&materials),
-    usage: wgpu::BufferUsages::STORAGE,
-    label: None,
-  });
-  let scene_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-    layout: &rt_pipeline.get_bind_group_layout(4),
-    entries: &[
-      wgpu::BindGroupEntry {
-        binding: 0,
-        resource: vtx_buf.as_entire_binding(),
+/// Whether going up to `samples` with the last `batch` passed a multiple of
+/// `every`.
+fn crossed(samples: u32, batch: u32, every: u32) -> bool {
+  samples / every > (samples - batch) / every
+}
+
+/// Replaces the first run of `#`s in `pattern` with `frame`, padded with zeros
+/// to the length of the run.
+fn frame_path(pattern: &str, frame: u32) -> String {
+  let Some(start) = pattern.find('#') else {
+    return pattern.into();
+  };
+  let len = pattern[start..].chars().take_while(|&c| c == '#').count();
+  format!(
+    "{}{:0len$}{}",
+    &pattern[..start],
+    frame,
+    &pattern[start + len..],
+  )
+}
+
+fn run_window(mut scene: Scene, opts: &Options) -> Result {
+  let event_loop = EventLoop::new()?;
+  let window = WindowBuilder::new().build(&event_loop)?;
+
+  let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+  let surface = unsafe { instance.create_surface(&window)? };
+  let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+    power_preference: wgpu::PowerPreference::HighPerformance,
+    compatible_surface: Some(&surface),
+    force_fallback_adapter: false,
+  }))
+  .unwrap();
+  let (device, queue) = request_device(&adapter)?;
+  let (width, height) = (opts.size.x, opts.size.y);
+  let mut renderer = Renderer::new(
+    device,
+    queue,
+    &mut scene,
+    width,
+    height,
+    opts.tile,
+    opts.wavefront,
+  )?;
+  let size = window.inner_size();
+  renderer.consts.window = Vec2::new(size.width as _, size.height as _);
+  renderer.consts.noise = opts.noise;
+  // with tiles, each is copied into place in a texture of the whole image as
+  // it's rendered, and each is sampled to the limits before moving on
+  let tiled = renderer.tiles().len() > 1;
+  let opts_size = opts.size;
+  let mut progress = match tiled {
+    true => Progress::new(opts.limits(TILE_SAMPLES, renderer.tiles().len())),
+    false => Progress::new(opts.limits(SAMPLES, 1)),
+  };
+  let max = renderer.device.limits().max_texture_dimension_2d;
+  if tiled && opts.size.max_element() > max {
+    return Err(format!("can't show more than {0}x{0}, use --render", max).into());
+  }
+  if tiled {
+    renderer.keep_image();
+  }
+  if opts.resume && checkpoint::resume(&mut renderer, &scene, &opts.checkpoint)? {
+    log::info!("resuming from {} samples", renderer.samples());
+  }
+  let (checkpoint, checkpoint_every) = (opts.checkpoint.clone(), opts.checkpoint_every);
+
+  // the render passes share their bind groups with the path tracer, so their
+  // layouts are spelled out rather than derived from the shader
+  let layouts = &renderer.layouts;
+  let quad_layout = renderer
+    .device
+    .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+      bind_group_layouts: &[&layouts.uniform, &layouts.sampler, &layouts.texture],
+      push_constant_ranges: &[],
+      label: None,
+    });
+  // the focus probe doesn't use the textures in groups 2 and 3
+  let empty_layout = renderer
+    .device
+    .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+      entries: &[],
+      label: None,
+    });
+  let focus_layout = renderer
+    .device
+    .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+      bind_group_layouts: &[
+        &layouts.uniform,
+        &layouts.sampler,
+        &empty_layout,
+        &empty_layout,
+        &layouts.scene,
+        &layouts.masks,
+      ],
+      push_constant_ranges: &[],
+      label: None,
+    });
+  let quad_pipeline = renderer
+    .device
+    .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+      layout: Some(&quad_layout),
+      vertex: wgpu::VertexState {
+        module: &renderer.shader,
+        entry_point: "quad_v",
+        buffers: &[],
       },
-      wgpu::BindGroupEntry {
-        binding: 1,
-        resource: material_buf.as_entire_binding(),
+      fragment: Some(wgpu::FragmentState {
+        module: &renderer.shader,
+        entry_point: "quad_f",
+        targets: &[Some(wgpu::ColorTargetState {
+          format: wgpu::TextureFormat::Bgra8Unorm,
+          blend: None,
+          write_mask: wgpu::ColorWrites::ALL,
+        })],
+      }),
+      primitive: wgpu::PrimitiveState::default(),
+      depth_stencil: None,
+      multisample: wgpu::MultisampleState::default(),
+      multiview: None,
+      label: None,
+    });
+  let ui_pipeline = renderer
+    .device
+    .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+      layout: Some(&quad_layout),
+      vertex: wgpu::VertexState {
+        module: &renderer.shader,
+        entry_point: "ui_v",
+        buffers: &[wgpu::VertexBufferLayout {
+          array_stride: mem::size_of::<Vertex>() as _,
+          step_mode: wgpu::VertexStepMode::Vertex,
+          attributes: &wgpu::vertex_attr_array![0 => Float32x2, 1 => Float32x2, 2 => Float32x3],
+        }],
       },
-    ],
-    label: None,
-  });
-
-  let sky = image::open("alps_field_4k.exr")?.to_rgba32f();
-  let sky_tex = device.create_texture_with_data(
-    // let sky_tex = device.create_texture(
-    &queue,
-    &wgpu::TextureDescriptor {
-      size: wgpu::Extent3d {
-        width: sky.width(),
-        height: sky.height(),
-        depth_or_array_layers: 1,
+      fragment: Some(wgpu::FragmentState {
+        module: &renderer.shader,
+        entry_point: "ui_f",
+        targets: &[Some(wgpu::ColorTargetState {
+          format: wgpu::TextureFormat::Bgra8Unorm,
+          blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+          write_mask: wgpu::ColorWrites::ALL,
+        })],
+      }),
+      primitive: wgpu::PrimitiveState::default(),
+      depth_stencil: None,
+      multisample: wgpu::MultisampleState::default(),
+      multiview: None,
+      label: None,
+    });
+  let focus_pipeline = renderer
+    .device
+    .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+      layout: Some(&focus_layout),
+      vertex: wgpu::VertexState {
+        module: &renderer.shader,
+        entry_point: "quad_v",
+        buffers: &[],
       },
-      mip_level_count: 1,
-      sample_count: 1,
-      dimension: wgpu::TextureDimension::D2,
-      format: wgpu::TextureFormat::Rgba32Float,
-      usage: wgpu::TextureUsages::TEXTURE_BINDING,
+      fragment: Some(wgpu::FragmentState {
+        module: &renderer.shader,
+        entry_point: "focus_f",
+        targets: &[Some(wgpu::ColorTargetState {
+          format: wgpu::TextureFormat::R32Float,
+          blend: None,
+          write_mask: wgpu::ColorWrites::ALL,
+        })],
+      }),
+      primitive: wgpu::PrimitiveState::default(),
+      depth_stencil: None,
+      multisample: wgpu::MultisampleState::default(),
+      multiview: None,
       label: None,
-      view_formats: &[],
+    });
+  let empty_bind_group = renderer
+    .device
+    .create_bind_group(&wgpu::BindGroupDescriptor {
+      layout: &empty_layout,
+      entries: &[],
+      label: None,
+    });
+  let focus_tex = renderer.device.create_texture(&wgpu::TextureDescriptor {
+    size: wgpu::Extent3d {
+      width: 1,
+      height: 1,
+      depth_or_array_layers: 1,
     },
-    cast_slice(&sky.as_raw()),
-  );
-  let sky_view = sky_tex.create_view(&wgpu::TextureViewDescriptor::default());
-  let sky_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-    layout: &tex_layout,
-    entries: &[wgpu::BindGroupEntry {
-      binding: 0,
-      resource: wgpu::BindingResource::TextureView(&sky_view),
-    }],
+    mip_level_count: 1,
+    sample_count: 1,
+    dimension: wgpu::TextureDimension::D2,
+    format: wgpu::TextureFormat::R32Float,
+    usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+    view_formats: &[],
+    label: None,
+  });
+  let focus_view = focus_tex.create_view(&wgpu::TextureViewDescriptor::default());
+  let focus_buf = renderer.device.create_buffer(&wgpu::BufferDescriptor {
+    size: mem::size_of::<f32>() as _,
+    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+    mapped_at_creation: false,
     label: None,
   });
+  let mut focus_at = None;
 
   let mut ctx = Context::new();
   ctx.fonts().add_font(include_bytes!("roboto.ttf"), 40.0)?;
-  let font_tex = device.create_texture_with_data(
-    &queue,
+  let font_tex = renderer.device.create_texture_with_data(
+    &renderer.queue,
     &wgpu::TextureDescriptor {
       size: wgpu::Extent3d {
         width: ctx.fonts().size().0,
@@ -245,31 +560,62 @@ fn main() -> Result {
     cast_slice(&ctx.fonts().build_tex()),
   );
   let font_view = font_tex.create_view(&wgpu::TextureViewDescriptor::default());
-  let font_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-    layout: &tex_layout,
-    entries: &[wgpu::BindGroupEntry {
-      binding: 0,
-      resource: wgpu::BindingResource::TextureView(&font_view),
-    }],
-    label: None,
+  let font_bind_group = renderer
+    .device
+    .create_bind_group(&wgpu::BindGroupDescriptor {
+      layout: &renderer.layouts.texture,
+      entries: &[wgpu::BindGroupEntry {
+        binding: 0,
+        resource: wgpu::BindingResource::TextureView(&font_view),
+      }],
+      label: None,
+    });
+
+  // the view blends render pixels when zoomed out
+  let linear = renderer.device.create_sampler(&wgpu::SamplerDescriptor {
+    mag_filter: wgpu::FilterMode::Linear,
+    min_filter: wgpu::FilterMode::Linear,
+    ..Default::default()
   });
+  let linear_bind_group = renderer
+    .device
+    .create_bind_group(&wgpu::BindGroupDescriptor {
+      layout: &renderer.layouts.sampler,
+      entries: &[wgpu::BindGroupEntry {
+        binding: 0,
+        resource: wgpu::BindingResource::Sampler(&linear),
+      }],
+      label: None,
+    });
 
-  let size = window.inner_size();
-  let mut textures = Textures::new(&device, &tex_layout, size.width, size.height);
-  let mut consts = Consts {
-    size: Vec2::new(size.width as _, size.height as _),
-    rand: rand::random(),
-    samples: 1,
-    zero: 0.0,
-  };
+  let mut view = View::new();
+  let mut ctrl = false;
+  let mut last_frame = Instant::now();
+  let mut frame = scene.frames.0 as f32;
+  let (fixed_batch, frame_time) = (opts.batch, opts.frame_time);
+  let mut batch = fixed_batch.unwrap_or(1);
+  let mut sampled = false;
+  let mut denoise = false;
 
   event_loop.run(move |event, elwt| {
     handle_ui_event(&mut ctx, &event);
+    if let Event::WindowEvent {
+      event: WindowEvent::ModifiersChanged(modifiers),
+      ..
+    } = &event
+    {
+      ctrl = modifiers.state().control_key();
+    }
+    // the wheel zooms the view unless it's moving the orbit camera
+    let wheel = scene.camera.mode == Mode::Fly || ctrl;
+    if !view.handle_event(&event, wheel, !ctx.wants_mouse()) {
+      scene.camera.handle_event(&event);
+    }
     match event {
       Event::WindowEvent { event, .. } => match event {
         WindowEvent::Resized(size) => {
           surface.configure(
-            &device,
+            &renderer.device,
             &wgpu::SurfaceConfiguration {
               usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
               format: wgpu::TextureFormat::Bgra8Unorm,
@@ -280,25 +626,104 @@ fn main() -> Result {
               view_formats: vec![],
             },
           );
-          textures = Textures::new(&device, &tex_layout, size.width, size.height);
-          consts.size = Vec2::new(size.width as _, size.height as _);
-          consts.samples = 1;
+          renderer.consts.window = Vec2::new(size.width as _, size.height as _);
+        }
+        WindowEvent::CloseRequested => {
+          if renderer.samples() > 0 {
+            if let Err(e) = checkpoint::save(&renderer, &scene, &checkpoint) {
+              log::error!("{}: {}", checkpoint, e);
+            }
+          }
+          elwt.exit();
+        }
+        WindowEvent::KeyboardInput {
+          event:
+            KeyEvent {
+              physical_key: PhysicalKey::Code(KeyCode::KeyF),
+              state: ElementState::Pressed,
+              ..
+            },
+          ..
+        } => scene.camera.frame(renderer.min, renderer.max),
+        WindowEvent::KeyboardInput {
+          event:
+            KeyEvent {
+              physical_key: PhysicalKey::Code(KeyCode::KeyP),
+              state: ElementState::Pressed,
+              repeat: false,
+              ..
+            },
+          ..
+        } => {
+          // the AOVs aren't kept for the whole image with tiles
+          let (sums, aovs, size) = match &renderer.image {
+            Some((texture, _)) => (renderer.read_texture(texture), None, opts_size),
+            None => (
+              renderer.read_raw(),
+              Some(renderer.read_aovs()),
+              renderer.canvas(),
+            ),
+          };
+          let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+          for ext in ["exr", "png"] {
+            let path = format!("render_{}.{}", secs, ext);
+            match aov::save(&sums, aovs.as_deref(), size, &path, 8) {
+              Ok(()) => log::info!("saved {}", path),
+              Err(e) => log::error!("{}: {}", path, e),
+            }
+          }
         }
-        WindowEvent::CloseRequested => elwt.exit(),
+        WindowEvent::MouseInput {
+          button: MouseButton::Middle,
+          state: ElementState::Pressed,
+          ..
+        } => focus_at = Some(ctx.input().cursor_pos),
         WindowEvent::RedrawRequested => {
           let surface = surface.get_current_texture().unwrap();
           let surface_view = surface
             .texture
             .create_view(&wgpu::TextureViewDescriptor::default());
-          let mut encoder =
-            device.create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+          let mut encoder = renderer
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
 
-          queue.write_buffer(&uniform_buf, 0, cast(&consts));
+          let now = Instant::now();
+          let dt = (now - last_frame).as_secs_f32();
+          // the last frame traced `consts.batch` samples if it isn't done yet
+          if sampled {
+            progress.add_time(dt);
+            if fixed_batch.is_none() {
+              batch = fit_batch(renderer.consts.batch, dt, frame_time);
+            }
+          }
+          if scene.camera.update(dt) {
+            scene.camera.write(&mut renderer.consts);
+            renderer.restart();
+            progress.restart();
+          }
+          last_frame = now;
+          let remaining = progress.remaining(renderer.samples());
+          renderer.consts.batch = batch.min(remaining).min(renderer.max_batch()).max(1);
 
-          if consts.samples <= SAMPLES {
-            let mut rt_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+          let (offset, scale) = view.update(renderer.consts.size, renderer.consts.window);
+          renderer.consts.view_offset = offset;
+          renderer.consts.view_scale = scale;
+          let probe = focus_at
+            .take()
+            .map(|pos| view.to_render(pos))
+            .filter(|pos| pos.cmpge(Vec2::ZERO).all() && pos.cmplt(renderer.consts.size).all());
+          if let Some(pos) = probe {
+            renderer.consts.probe = pos.floor() + 0.5;
+          }
+          renderer.write_consts();
+
+          if probe.is_some() {
+            let mut focus_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
               color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &textures.current_view,
+                view: &focus_view,
                 resolve_target: None,
                 ops: wgpu::Operations {
                   load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
@@ -308,31 +733,45 @@ fn main() -> Result {
               depth_stencil_attachment: None,
               label: None,
             });
-            rt_pass.set_pipeline(&rt_pipeline);
-            rt_pass.set_bind_group(0, &uniform_bind_group, &[]);
-            rt_pass.set_bind_group(1, &sampler_bind_group, &[]);
-            rt_pass.set_bind_group(2, &textures.prev_bind_group, &[]);
-            rt_pass.set_bind_group(3, &sky_bind_group, &[]);
-            rt_pass.set_bind_group(4, &scene_bind_group, &[]);
-            rt_pass.draw(0..3, 0..1);
-            drop(rt_pass);
-            encoder.copy_texture_to_texture(
+            focus_pass.set_pipeline(&focus_pipeline);
+            focus_pass.set_bind_group(0, &renderer.uniform_bind_group, &[]);
+            focus_pass.set_bind_group(1, &renderer.sampler_bind_group, &[]);
+            focus_pass.set_bind_group(2, &empty_bind_group, &[]);
+            focus_pass.set_bind_group(3, &empty_bind_group, &[]);
+            focus_pass.set_bind_group(4, &renderer.scene_bind_group, &[]);
+            focus_pass.set_bind_group(5, &renderer.mask_bind_group, &[]);
+            focus_pass.draw(0..3, 0..1);
+            drop(focus_pass);
+            encoder.copy_texture_to_buffer(
               wgpu::ImageCopyTexture {
-                texture: &textures.current,
+                texture: &focus_tex,
                 mip_level: 0,
                 origin: wgpu::Origin3d::default(),
                 aspect: wgpu::TextureAspect::All,
               },
-              wgpu::ImageCopyTexture {
-                texture: &textures.prev,
-                mip_level: 0,
-                origin: wgpu::Origin3d::default(),
-                aspect: wgpu::TextureAspect::All,
+              wgpu::ImageCopyBuffer {
+                buffer: &focus_buf,
+                layout: wgpu::ImageDataLayout::default(),
               },
-              textures.prev.size(),
+              focus_tex.size(),
             );
-            consts.rand = rand::random();
-            consts.samples += 1;
+          }
+
+          sampled = progress.running(renderer.samples());
+          if sampled {
+            renderer.sample(&mut encoder);
+            renderer.copy_tile(&mut encoder);
+            renderer.advance();
+          }
+          // tiles are shown as they're assembled, the denoiser only sees one
+          // and so do the AOVs
+          let aov = Aov::from(renderer.consts.aov);
+          let aov_shown = !tiled && !matches!(aov, Aov::Image | Aov::Samples);
+          let denoised = denoise && !tiled && !aov_shown;
+          if aov_shown {
+            renderer.show_aov(&mut encoder);
+          } else if denoised {
+            renderer.denoise(&mut encoder);
           }
 
           let mut quad_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
@@ -348,25 +787,150 @@ fn main() -> Result {
             label: None,
           });
           quad_pass.set_pipeline(&quad_pipeline);
-          quad_pass.set_bind_group(0, &uniform_bind_group, &[]);
-          quad_pass.set_bind_group(1, &sampler_bind_group, &[]);
-          quad_pass.set_bind_group(2, &textures.prev_bind_group, &[]);
+          quad_pass.set_bind_group(0, &renderer.uniform_bind_group, &[]);
+          quad_pass.set_bind_group(1, &linear_bind_group, &[]);
+          let shown = match (&renderer.image, &renderer.denoiser) {
+            (Some((_, bind_group)), _) => bind_group,
+            (None, _) if aov_shown => &renderer.aov_view.bind_group,
+            (None, Some(denoiser)) if denoised => &denoiser.bind_group,
+            _ => &renderer.textures.bind_group,
+          };
+          quad_pass.set_bind_group(2, shown, &[]);
           quad_pass.draw(0..3, 0..1);
           drop(quad_pass);
 
           let mut ui = ctx.begin_frame();
-          ui.text(&format!("{}/{}", consts.samples - 1, SAMPLES));
+          if tiled {
+            let tiles = renderer.tiles().len();
+            ui.text(&format!("tile {}/{}", renderer.tile() + 1, tiles));
+          }
+          let limit = match progress.unlimited {
+            true => "-".to_string(),
+            false => progress.limits.samples.to_string(),
+          };
+          ui.text(&format!(
+            "{}/{} ({}/frame) {:.0}s",
+            renderer.samples(),
+            limit,
+            renderer.consts.batch,
+            progress.elapsed,
+          ));
+          if let Some(error) = progress.error {
+            ui.text(&format!("error {:.4}", error));
+          }
+          let pause = match progress.paused {
+            true => "resume",
+            false => "pause",
+          };
+          if ui.button(pause) {
+            progress.apply(match progress.paused {
+              true => Command::Resume,
+              false => Command::Pause,
+            });
+          }
+          if progress.reached(renderer.samples()).is_some() {
+            if ui.button("continue") {
+              progress.apply(Command::Continue);
+            }
+          } else if ui.button("stop") {
+            progress.apply(Command::Stop);
+          }
+          let mode = match scene.camera.mode {
+            Mode::Fly => "fly",
+            Mode::Orbit => "orbit",
+          };
+          if ui.button(mode) {
+            scene.camera.toggle_mode();
+          }
+          let zoom = match view.fit {
+            true => "fit".to_string(),
+            false => format!("{:.0}%", view.scale * 100.0),
+          };
+          if ui.button(&zoom) {
+            match view.fit {
+              true => view.actual_size(),
+              false => view.fit = true,
+            }
+          }
+          if ui.button("frame") {
+            scene.camera.frame(renderer.min, renderer.max);
+          }
+          // tiles only keep the image and its sample counts
+          let aovs = match tiled {
+            true => &Aov::ALL[..2],
+            false => &Aov::ALL[..],
+          };
+          let aov = Aov::from(renderer.consts.aov);
+          let shown = format!("{:?}", aov).to_lowercase();
+          if ui.button(&shown) {
+            let i = aovs.iter().position(|a| *a == aov).map_or(0, |i| i + 1);
+            renderer.consts.aov = aovs[i % aovs.len()] as u32;
+          }
+          if !tiled {
+            let filter = match denoise {
+              true => "denoised",
+              false => "noisy",
+            };
+            if ui.button(filter) {
+              denoise = !denoise;
+              renderer.keep_denoiser();
+            }
+          }
+          // only decides which pixels get more samples, nothing to restart
+          ui.slider("noise", &mut renderer.consts.noise, 0.0, 0.1);
+          let projection = match scene.camera.projection {
+            Projection::Perspective => "perspective",
+            Projection::Orthographic => "orthographic",
+            Projection::Equirect => "equirect",
+            Projection::Fisheye => "fisheye",
+          };
+          if ui.button(projection) {
+            scene.camera.projection = match scene.camera.projection {
+              Projection::Perspective => Projection::Orthographic,
+              Projection::Orthographic => Projection::Equirect,
+              Projection::Equirect => Projection::Fisheye,
+              Projection::Fisheye => Projection::Perspective,
+            };
+            scene.camera.mark_changed();
+          }
+          let lens = [
+            ui.slider("fov", &mut scene.camera.fov, 0.05, 1.5),
+            ui.slider("aperture", &mut scene.camera.aperture, 0.0, 0.5),
+            ui.slider("focus", &mut scene.camera.focus, 0.1, 50.0),
+            ui.slider("ortho size", &mut scene.camera.ortho_size, 0.1, 50.0),
+          ];
+          let mut blades = scene.camera.blades as f32;
+          if ui.slider("blades", &mut blades, 0.0, 12.0)
+            && blades.round() as u32 != scene.camera.blades
+          {
+            scene.camera.blades = blades.round() as u32;
+            scene.camera.mark_changed();
+          }
+          let rotation = ui.slider("rotation", &mut scene.camera.blade_rotation, 0.0, PI);
+          if lens.contains(&true) || rotation {
+            scene.camera.mark_changed();
+          }
+          let (first, last) = scene.frames;
+          if last > first && ui.slider("timeline", &mut frame, first as f32, last as f32) {
+            scene.animate(frame.round());
+            renderer.set_scene(&scene);
+            progress.restart();
+          }
           let out = ctx.end_frame();
-          let vtx_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            contents: cast_slice(&out.vtx_buf),
-            usage: wgpu::BufferUsages::VERTEX,
-            label: None,
-          });
-          let idx_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            contents: cast_slice(&out.idx_buf),
-            usage: wgpu::BufferUsages::INDEX,
-            label: None,
-          });
+          let vtx_buf = renderer
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+              contents: cast_slice(&out.vtx_buf),
+              usage: wgpu::BufferUsages::VERTEX,
+              label: None,
+            });
+          let idx_buf = renderer
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+              contents: cast_slice(&out.idx_buf),
+              usage: wgpu::BufferUsages::INDEX,
+              label: None,
+            });
           let mut ui_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             color_attachments: &[Some(wgpu::RenderPassColorAttachment {
               view: &surface_view,
@@ -380,15 +944,45 @@ fn main() -> Result {
             label: None,
           });
           ui_pass.set_pipeline(&ui_pipeline);
-          ui_pass.set_bind_group(0, &uniform_bind_group, &[]);
-          ui_pass.set_bind_group(1, &sampler_bind_group, &[]);
+          ui_pass.set_bind_group(0, &renderer.uniform_bind_group, &[]);
+          ui_pass.set_bind_group(1, &renderer.sampler_bind_group, &[]);
           ui_pass.set_bind_group(2, &font_bind_group, &[]);
           ui_pass.set_vertex_buffer(0, vtx_buf.slice(..));
           ui_pass.set_index_buffer(idx_buf.slice(..), wgpu::IndexFormat::Uint32);
           ui_pass.draw_indexed(0..out.idx_buf.len() as _, 0, 0..1);
           drop(ui_pass);
 
-          queue.submit([encoder.finish()]);
+          renderer.queue.submit([encoder.finish()]);
+          let every = checkpoint_every;
+          if sampled && every > 0 && crossed(renderer.samples(), renderer.consts.batch, every) {
+            if let Err(e) = checkpoint::save(&renderer, &scene, &checkpoint) {
+              log::error!("{}: {}", checkpoint, e);
+            }
+          }
+          if sampled && progress.needs_estimate() {
+            progress.set_error(renderer.error());
+          }
+          if sampled && progress.needs_taken(renderer.samples()) {
+            progress.set_taken(renderer.taken());
+          }
+          let next = renderer.tile() + 1;
+          let reached = progress.reached(renderer.samples());
+          // a stop holds the current tile until told to continue
+          if reached.is_some_and(|r| r != "stop") && next < renderer.tiles().len() {
+            renderer.set_tile(next);
+            progress.restart();
+          }
+          if probe.is_some() {
+            let slice = focus_buf.slice(..);
+            slice.map_async(wgpu::MapMode::Read, |_| {});
+            renderer.device.poll(wgpu::Maintain::Wait);
+            let distance = f32::from_ne_bytes(slice.get_mapped_range()[..4].try_into().unwrap());
+            focus_buf.unmap();
+            if distance > 0.0 {
+              scene.camera.focus = distance;
+              scene.camera.mark_changed();
+            }
+          }
           surface.present();
           instance.poll_all(true);
         }
@@ -402,63 +996,6 @@ fn main() -> Result {
   Ok(())
 }
 
-struct Textures {
-  current: wgpu::Texture,
-  current_view: wgpu::TextureView,
-  prev: wgpu::Texture,
-  prev_bind_group: wgpu::BindGroup,
-}
-
-impl Textures {
-  fn new(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, width: u32, height: u32) -> Self {
-    let size = wgpu::Extent3d {
-      width,
-      height,
-      depth_or_array_layers: 1,
-    };
-    let current = device.create_texture(&wgpu::TextureDescriptor {
-      size,
-      mip_level_count: 1,
-      sample_count: 1,
-      dimension: wgpu::TextureDimension::D2,
-      format: wgpu::TextureFormat::Rgba32Float,
-      usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
-      view_formats: &[],
-      label: None,
-    });
-    let current_view = current.create_view(&wgpu::TextureViewDescriptor::default());
-    let prev = device.create_texture(&wgpu::TextureDescriptor {
-      size: wgpu::Extent3d {
-        width: size.width,
-        height: size.height,
-        depth_or_array_layers: 1,
-      },
-      mip_level_count: 1,
-      sample_count: 1,
-      dimension: wgpu::TextureDimension::D2,
-      format: wgpu::TextureFormat::Rgba32Float,
-      usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
-      view_formats: &[],
-      label: None,
-    });
-    let prev_view = prev.create_view(&wgpu::TextureViewDescriptor::default());
-    let prev_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-      layout,
-      entries: &[wgpu::BindGroupEntry {
-        binding: 0,
-        resource: wgpu::BindingResource::TextureView(&prev_view),
-      }],
-      label: None,
-    });
-    Self {
-      current,
-      current_view,
-      prev,
-      prev_bind_group,
-    }
-  }
-}
-
 fn cast_slice<T>(t: &[T]) -> &[u8] {
   unsafe { slice::from_raw_parts(t.as_ptr() as _, mem::size_of_val(t)) }
 }
@@ -477,8 +1014,8 @@ fn handle_ui_event<T>(ctx: &mut Context, event: &Event<T>) {
       WindowEvent::MouseInput { button, state, .. } => {
         input.mouse_buttons[match button {
           MouseButton::Left => 0,
+          MouseButton::Right => 1,
           MouseButton::Middle => 2,
-          MouseButton::Right => 3,
           _ => return,
         }] = *state == ElementState::Pressed;
       }
@@ -487,3 +1024,34 @@ fn handle_ui_event<T>(ctx: &mut Context, event: &Event<T>) {
     _ => {}
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn fit_batch_moves_towards_the_target_at_most_twofold() {
+    assert_eq!(fit_batch(8, 0.05, 0.1), 16);
+    assert_eq!(fit_batch(8, 0.01, 0.1), 16);
+    assert_eq!(fit_batch(8, 0.4, 0.1), 4);
+    assert_eq!(fit_batch(8, 0.08, 0.1), 10);
+    assert_eq!(fit_batch(1, 1.0, 0.1), 1);
+    assert_eq!(fit_batch(MAX_BATCH, 0.0, 0.1), MAX_BATCH);
+  }
+
+  #[test]
+  fn crossed_catches_multiples_inside_a_batch() {
+    assert!(crossed(100, 1, 100));
+    assert!(!crossed(101, 1, 100));
+    assert!(crossed(104, 8, 100));
+    assert!(!crossed(99, 8, 100));
+    assert!(crossed(205, 200, 100));
+  }
+
+  #[test]
+  fn frame_path_pads_the_first_run_of_hashes() {
+    assert_eq!(frame_path("frame_####.exr", 7), "frame_0007.exr");
+    assert_eq!(frame_path("f#_#.png", 12), "f12_#.png");
+    assert_eq!(frame_path("render.exr", 3), "render.exr");
+  }
+}
@@ -0,0 +1,302 @@
+use std::io::BufRead;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+/// Seconds of sampling between estimates of the error, which has to read the
+/// whole image back.
+const ESTIMATE_EVERY: f32 = 1.0;
+
+/// When to stop sampling, at whichever limit is reached first.
+#[derive(Clone, Copy)]
+pub struct Limits {
+  /// Samples per pixel, on average once converged pixels are skipped.
+  pub samples: u32,
+  /// Seconds spent sampling, pauses excluded.
+  pub time: Option<f32>,
+  /// Mean relative error of the pixels, estimated from their variance.
+  pub error: Option<f32>,
+}
+
+/// Pausing and stopping, typed on standard input without a window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+  Pause,
+  Resume,
+  /// Keep sampling past the limits.
+  Continue,
+  /// Stop sampling now, or at the limits again after `Continue`.
+  Stop,
+}
+
+impl Command {
+  fn parse(line: &str) -> Option<Self> {
+    match line.trim() {
+      "p" | "pause" => Some(Self::Pause),
+      "r" | "resume" => Some(Self::Resume),
+      "c" | "continue" => Some(Self::Continue),
+      "s" | "stop" => Some(Self::Stop),
+      _ => None,
+    }
+  }
+}
+
+/// Reads commands from standard input, one per line, on a thread of its own.
+/// The channel disconnects when input ends.
+pub fn commands() -> Receiver<Command> {
+  let (tx, rx) = mpsc::channel();
+  thread::spawn(move || {
+    for line in std::io::stdin().lock().lines() {
+      let Ok(line) = line else { break };
+      match Command::parse(&line) {
+        Some(cmd) => {
+          if tx.send(cmd).is_err() {
+            break;
+          }
+        }
+        None => log::warn!(
+          "unknown command {:?}, try pause, resume, continue or stop",
+          line
+        ),
+      }
+    }
+  });
+  rx
+}
+
+/// How far a render has come towards its limits.
+pub struct Progress {
+  pub limits: Limits,
+  /// Seconds spent sampling since the start.
+  pub elapsed: f32,
+  /// Last estimate of the mean relative error, if one is being kept.
+  pub error: Option<f32>,
+  /// Value of `elapsed` at the last estimate.
+  estimated: f32,
+  /// Mean samples per pixel taken, read back once as many have been
+  /// dispatched as the limit allows. While converged pixels are skipped it
+  /// falls behind, and counts against the limit instead, so the samples they
+  /// skip go to the noisy ones.
+  taken: Option<f32>,
+  /// Whether no pixel took a sample between the last two readings of `taken`.
+  converged: bool,
+  pub paused: bool,
+  /// Whether the limits are ignored.
+  pub unlimited: bool,
+  stopped: bool,
+}
+
+impl Progress {
+  pub fn new(limits: Limits) -> Self {
+    Self {
+      limits,
+      elapsed: 0.0,
+      error: None,
+      estimated: 0.0,
+      taken: None,
+      converged: false,
+      paused: false,
+      unlimited: false,
+      stopped: false,
+    }
+  }
+
+  /// Starts over from no samples, staying paused if it was.
+  pub fn restart(&mut self) {
+    *self = Self {
+      paused: self.paused,
+      ..Self::new(self.limits)
+    };
+  }
+
+  /// The limit that stopped sampling at `samples` per pixel, if any.
+  pub fn reached(&self, samples: u32) -> Option<&'static str> {
+    if self.stopped {
+      return Some("stop");
+    }
+    if self.unlimited {
+      return None;
+    }
+    let limits = &self.limits;
+    if self.converged {
+      Some("noise")
+    } else if self.taken.unwrap_or(samples as f32) >= limits.samples as f32 {
+      Some("samples")
+    } else if limits.time.is_some_and(|t| self.elapsed >= t) {
+      Some("time")
+    } else if limits
+      .error
+      .is_some_and(|e| self.error.is_some_and(|error| error <= e))
+    {
+      Some("error")
+    } else {
+      None
+    }
+  }
+
+  /// Whether there's sampling left to do at `samples`, and it isn't paused.
+  pub fn running(&self, samples: u32) -> bool {
+    !self.paused && self.reached(samples).is_none()
+  }
+
+  /// Most samples per pixel left before the sample limit.
+  pub fn remaining(&self, samples: u32) -> u32 {
+    match (self.unlimited, self.taken) {
+      (true, _) => u32::MAX,
+      (false, Some(taken)) => (self.limits.samples as f32 - taken).ceil().max(1.0) as u32,
+      (false, None) => self.limits.samples.saturating_sub(samples),
+    }
+  }
+
+  /// Whether the sample limit needs to know how many samples were taken, as
+  /// `samples` have been dispatched.
+  pub fn needs_taken(&self, samples: u32) -> bool {
+    !self.unlimited && samples >= self.limits.samples
+  }
+
+  /// Counts the mean samples per pixel taken against the sample limit, `None`
+  /// if every pixel takes every sample dispatched.
+  pub fn set_taken(&mut self, taken: Option<f32>) {
+    self.converged = taken.is_some() && taken == self.taken;
+    self.taken = taken;
+  }
+
+  /// Counts `secs` more of sampling.
+  pub fn add_time(&mut self, secs: f32) {
+    self.elapsed += secs;
+  }
+
+  /// Whether the error limit needs a new estimate.
+  pub fn needs_estimate(&self) -> bool {
+    self.limits.error.is_some()
+      && (self.error.is_none() || self.elapsed - self.estimated >= ESTIMATE_EVERY)
+  }
+
+  pub fn set_error(&mut self, error: f32) {
+    self.error = Some(error);
+    self.estimated = self.elapsed;
+  }
+
+  pub fn apply(&mut self, cmd: Command) {
+    match cmd {
+      Command::Pause => self.paused = true,
+      Command::Resume => self.paused = false,
+      Command::Continue => {
+        self.unlimited = true;
+        self.stopped = false;
+        self.paused = false;
+      }
+      Command::Stop if self.unlimited => self.unlimited = false,
+      Command::Stop => self.stopped = true,
+    }
+  }
+
+  /// Applies the commands received so far, waiting for more while paused.
+  /// Resumes if the commands run out for good.
+  pub fn poll(&mut self, commands: &Receiver<Command>) {
+    for cmd in commands.try_iter() {
+      self.apply(cmd);
+    }
+    while self.paused {
+      match commands.recv() {
+        Ok(cmd) => self.apply(cmd),
+        Err(_) => self.paused = false,
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const LIMITS: Limits = Limits {
+    samples: 100,
+    time: Some(10.0),
+    error: Some(0.05),
+  };
+
+  #[test]
+  fn commands_parse_in_full_or_by_initial() {
+    assert_eq!(Command::parse("p"), Some(Command::Pause));
+    assert_eq!(Command::parse("resume"), Some(Command::Resume));
+    assert_eq!(Command::parse(" c "), Some(Command::Continue));
+    assert_eq!(Command::parse("stop\n"), Some(Command::Stop));
+    assert!(Command::parse("quit").is_none());
+  }
+
+  #[test]
+  fn reached_picks_the_first_limit() {
+    let mut progress = Progress::new(LIMITS);
+    assert_eq!(progress.reached(99), None);
+    assert_eq!(progress.reached(100), Some("samples"));
+    progress.add_time(10.0);
+    assert_eq!(progress.reached(50), Some("time"));
+    let mut progress = Progress::new(LIMITS);
+    assert!(progress.needs_estimate());
+    progress.set_error(0.04);
+    assert!(!progress.needs_estimate());
+    assert_eq!(progress.reached(50), Some("error"));
+  }
+
+  #[test]
+  fn stop_and_continue() {
+    let mut progress = Progress::new(LIMITS);
+    progress.apply(Command::Stop);
+    assert_eq!(progress.reached(10), Some("stop"));
+    assert!(!progress.running(10));
+    progress.apply(Command::Continue);
+    assert_eq!(progress.reached(1000), None);
+    assert_eq!(progress.remaining(1000), u32::MAX);
+    // the first stop after continuing goes back to the limits
+    progress.apply(Command::Stop);
+    assert_eq!(progress.reached(10), None);
+    assert_eq!(progress.reached(100), Some("samples"));
+    progress.apply(Command::Stop);
+    assert_eq!(progress.reached(10), Some("stop"));
+  }
+
+  #[test]
+  fn pause_holds_sampling_until_resumed() {
+    let mut progress = Progress::new(LIMITS);
+    progress.apply(Command::Pause);
+    assert!(!progress.running(10));
+    progress.restart();
+    assert!(progress.paused);
+    progress.apply(Command::Resume);
+    assert!(progress.running(10));
+  }
+
+  #[test]
+  fn remaining_counts_down_to_the_sample_limit() {
+    let progress = Progress::new(LIMITS);
+    assert_eq!(progress.remaining(0), 100);
+    assert_eq!(progress.remaining(96), 4);
+    assert_eq!(progress.remaining(120), 0);
+  }
+
+  #[test]
+  fn samples_skipped_by_converged_pixels_go_to_the_rest() {
+    let mut progress = Progress::new(LIMITS);
+    assert!(!progress.needs_taken(99));
+    assert!(progress.needs_taken(100));
+    progress.set_taken(Some(60.0));
+    assert_eq!(progress.reached(100), None);
+    assert_eq!(progress.remaining(100), 40);
+    progress.set_taken(Some(99.5));
+    assert_eq!(progress.remaining(140), 1);
+    progress.set_taken(Some(100.0));
+    assert_eq!(progress.reached(140), Some("samples"));
+  }
+
+  #[test]
+  fn stops_once_every_pixel_has_converged() {
+    let mut progress = Progress::new(LIMITS);
+    progress.set_taken(Some(60.0));
+    progress.set_taken(Some(60.0));
+    assert_eq!(progress.reached(120), Some("noise"));
+    // without a noise threshold every pixel takes every sample
+    progress.set_taken(None);
+    assert_eq!(progress.reached(99), None);
+    assert_eq!(progress.reached(100), Some("samples"));
+  }
+}
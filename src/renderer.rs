@@ -0,0 +1,1143 @@
+use std::mem;
+use std::io::BufReader;
+use std::fs::File;
+use std::path::Path;
+use wgpu::util::DeviceExt;
+use glam::{BVec3, UVec2, Vec2, Vec3};
+use obj::raw::{parse_obj, Polygon};
+use shared::{luminance, unreal, Aov, Consts, AOV_LAYERS, FLAT_SAMPLES};
+use crate::scene::Scene;
+use crate::aov::AovView;
+use crate::checkpoint::{self, HASH_START};
+use crate::denoise::Denoiser;
+use crate::wavefront::Wavefront;
+use crate::{cast, cast_slice, Result};
+
+/// Side of the square workgroups of `main_c` and `wf_generate`.
+pub const WORKGROUP: u32 = 8;
+
+/// The path tracer and everything it reads, without any window. Each call to
+/// `sample` adds `consts.batch` samples per pixel to the accumulated image.
+pub struct Renderer {
+  pub device: wgpu::Device,
+  pub queue: wgpu::Queue,
+  pub shader: wgpu::ShaderModule,
+  pub layouts: Layouts,
+  pub uniform_bind_group: wgpu::BindGroup,
+  pub sampler_bind_group: wgpu::BindGroup,
+  pub scene_bind_group: wgpu::BindGroup,
+  pub mask_bind_group: wgpu::BindGroup,
+  pub textures: Textures,
+  /// With tiles in the window, the whole image that `copy_tile` puts each
+  /// tile into, and a bind group to show it with.
+  pub image: Option<(wgpu::Texture, wgpu::BindGroup)>,
+  /// Set up by `keep_denoiser` the first time the window denoises.
+  pub denoiser: Option<Denoiser>,
+  pub aov_view: AovView,
+  /// Hash of the loaded mesh, sky, masks and aperture, for telling whether a
+  /// checkpoint was saved with the same files.
+  pub assets: u64,
+  /// World-space bounds of the mesh and the spheres over the frame, as of the
+  /// last `set_scene`.
+  pub min: Vec3,
+  pub max: Vec3,
+  pub consts: Consts,
+  tiles: Vec<UVec2>,
+  tile: usize,
+  rt_pipeline: wgpu::ComputePipeline,
+  /// Set to trace with the wavefront kernels instead of `rt_pipeline`.
+  wavefront: Option<Wavefront>,
+  uniform_buf: wgpu::Buffer,
+  sphere_buf: wgpu::Buffer,
+  instance_buf: wgpu::Buffer,
+  material_buf: wgpu::Buffer,
+  sky_bind_group: wgpu::BindGroup,
+  bokeh_bind_group: wgpu::BindGroup,
+}
+
+impl Renderer {
+  /// Loads everything `scene` refers to. The triangle range and bounds of the
+  /// mesh are filled into `scene.mesh`. With `tile` set, the image is rendered
+  /// in square tiles of that size instead of all at once. With `wavefront`
+  /// set, paths are traced by the wavefront integrator.
+  pub fn new(
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    scene: &mut Scene,
+    width: u32,
+    height: u32,
+    tile: Option<u32>,
+    wavefront: bool,
+  ) -> Result<Self> {
+    let shader = device.create_shader_module(wgpu::include_spirv!(env!("shaders.spv")));
+    let layouts = Layouts::new(&device);
+    let rt_layouts = [
+      &layouts.uniform,
+      &layouts.sampler,
+      &layouts.storage,
+      &layouts.texture,
+      &layouts.scene,
+      &layouts.masks,
+      &layouts.bokeh,
+    ];
+    let rt_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+      bind_group_layouts: &rt_layouts,
+      push_constant_ranges: &[],
+      label: None,
+    });
+    let rt_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+      layout: Some(&rt_layout),
+      module: &shader,
+      entry_point: "main_c",
+      label: None,
+    });
+
+    let uniform_buf = device.create_buffer(&wgpu::BufferDescriptor {
+      size: mem::size_of::<Consts>() as _,
+      usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+      mapped_at_creation: false,
+      label: None,
+    });
+    let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+      layout: &layouts.uniform,
+      entries: &[wgpu::BindGroupEntry {
+        binding: 0,
+        resource: uniform_buf.as_entire_binding(),
+      }],
+      label: None,
+    });
+
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+    let sampler_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+      layout: &layouts.sampler,
+      entries: &[wgpu::BindGroupEntry {
+        binding: 0,
+        resource: wgpu::BindingResource::Sampler(&sampler),
+      }],
+      label: None,
+    });
+
+    let obj = parse_obj(BufReader::new(File::open("untitled.obj")?))?;
+    let mut min = Vec3::MAX;
+    let mut max = Vec3::MIN;
+    let mut verts = vec![];
+    let mut uvs = vec![];
+    for polygon in &obj.polygons {
+      let corners = match polygon {
+        Polygon::P(p) => p.iter().map(|&p| (p, None)).collect::<Vec<_>>(),
+        Polygon::PT(p) => p.iter().map(|&(p, t)| (p, Some(t))).collect(),
+        Polygon::PN(p) => p.iter().map(|&(p, _)| (p, None)).collect(),
+        Polygon::PTN(p) => p.iter().map(|&(p, t, _)| (p, Some(t))).collect(),
+      };
+      // points and lines have no surface to hit
+      if corners.len() < 3 {
+        continue;
+      }
+      for i in 1..corners.len() - 1 {
+        let tri = [corners[0], corners[i], corners[i + 1]];
+        let pos = tri.map(|(p, _)| {
+          let (x, y, z, _) = obj.positions[p];
+          Vec3::new(x, y, z)
+        });
+        let uv = tri.map(|(_, t)| {
+          t.map_or(Vec2::ZERO, |t| {
+            let (u, v, _) = obj.tex_coords[t];
+            Vec2::new(u, 1.0 - v)
+          })
+        });
+        // the w of the three vertices holds the tangent of the triangle
+        let tangent = face_tangent(pos, uv);
+        for k in 0..3 {
+          min = min.min(pos[k]);
+          max = max.max(pos[k]);
+          verts.push(pos[k].extend(tangent[k]));
+          uvs.push(uv[k]);
+        }
+      }
+    }
+    let vtx_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+      contents: cast_slice(&verts),
+      usage: wgpu::BufferUsages::STORAGE,
+      label: None,
+    });
+    let uv_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+      contents: cast_slice(&uvs),
+      usage: wgpu::BufferUsages::STORAGE,
+      label: None,
+    });
+    let mut assets = checkpoint::hash(HASH_START, cast_slice(&verts));
+    assets = checkpoint::hash(assets, cast_slice(&uvs));
+    log::info!("{} {} {}", min, max, verts.len());
+    scene.mesh.end = verts.len() as u32 / 3;
+    scene.mesh.min = min;
+    scene.mesh.max = max;
+    let (min, max) = bounds(scene);
+    let sphere_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+      contents: cast_slice(&scene.spheres),
+      usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+      label: None,
+    });
+    let instance_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+      contents: cast_slice(&[scene.mesh]),
+      usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+      label: None,
+    });
+    let material_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+      contents: cast_slice(&scene.materials),
+      usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+      label: None,
+    });
+    let scene_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+      layout: &layouts.scene,
+      entries: &[
+        wgpu::BindGroupEntry {
+          binding: 0,
+          resource: vtx_buf.as_entire_binding(),
+        },
+        wgpu::BindGroupEntry {
+          binding: 1,
+          resource: material_buf.as_entire_binding(),
+        },
+        wgpu::BindGroupEntry {
+          binding: 2,
+          resource: uv_buf.as_entire_binding(),
+        },
+        wgpu::BindGroupEntry {
+          binding: 3,
+          resource: sphere_buf.as_entire_binding(),
+        },
+        wgpu::BindGroupEntry {
+          binding: 4,
+          resource: instance_buf.as_entire_binding(),
+        },
+      ],
+      label: None,
+    });
+
+    let sky = image::open("alps_field_4k.exr")?.to_rgba32f();
+    let sky_tex = device.create_texture_with_data(
+      // let sky_tex = device.create_texture(
+      &queue,
+      &wgpu::TextureDescriptor {
+        size: wgpu::Extent3d {
+          width: sky.width(),
+          height: sky.height(),
+          depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba32Float,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING,
+        label: None,
+        view_formats: &[],
+      },
+      cast_slice(&sky.as_raw()),
+    );
+    assets = checkpoint::hash(assets, cast_slice(sky.as_raw()));
+    let sky_view = sky_tex.create_view(&wgpu::TextureViewDescriptor::default());
+    let sky_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+      layout: &layouts.texture,
+      entries: &[wgpu::BindGroupEntry {
+        binding: 0,
+        resource: wgpu::BindingResource::TextureView(&sky_view),
+      }],
+      label: None,
+    });
+
+    // alpha masks, indexed by `Material::alpha_texture`
+    let masks = load_masks(&scene.masks)?;
+    for mask in &masks {
+      assets = checkpoint::hash(assets, mask.as_raw());
+    }
+    let mask_tex = device.create_texture_with_data(
+      &queue,
+      &wgpu::TextureDescriptor {
+        size: wgpu::Extent3d {
+          width: masks[0].width(),
+          height: masks[0].height(),
+          depth_or_array_layers: masks.len() as _,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING,
+        label: None,
+        view_formats: &[],
+      },
+      &masks
+        .iter()
+        .flat_map(|m| m.as_raw())
+        .copied()
+        .collect::<Vec<_>>(),
+    );
+    let mask_view = mask_tex.create_view(&wgpu::TextureViewDescriptor {
+      dimension: Some(wgpu::TextureViewDimension::D2Array),
+      ..Default::default()
+    });
+    let mask_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+      layout: &layouts.masks,
+      entries: &[wgpu::BindGroupEntry {
+        binding: 0,
+        resource: wgpu::BindingResource::TextureView(&mask_view),
+      }],
+      label: None,
+    });
+
+    let (bokeh_size, bokeh) = match &scene.bokeh {
+      Some(path) => bokeh_cdf(path)?,
+      None => (UVec2::ZERO, vec![0.0]),
+    };
+    assets = checkpoint::hash(assets, cast_slice(&bokeh));
+    let bokeh_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+      contents: cast_slice(&bokeh),
+      usage: wgpu::BufferUsages::STORAGE,
+      label: None,
+    });
+    let bokeh_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+      layout: &layouts.bokeh,
+      entries: &[wgpu::BindGroupEntry {
+        binding: 0,
+        resource: bokeh_buf.as_entire_binding(),
+      }],
+      label: None,
+    });
+
+    let canvas = tile.map_or(UVec2::new(width, height), |tile| {
+      UVec2::splat(tile).min(UVec2::new(width, height))
+    });
+    let limit = device.limits().max_texture_dimension_2d;
+    if canvas.max_element() > limit {
+      return Err(format!("can't render more than {0}x{0} at once, use tiles", limit).into());
+    }
+    let textures = Textures::new(&device, &layouts, canvas.x, canvas.y);
+    let aov_view = AovView::new(
+      &device,
+      &shader,
+      &layouts.uniform,
+      &layouts.texture,
+      [&textures.sums, &textures.aovs],
+      canvas,
+    );
+    let wavefront = wavefront.then(|| Wavefront::new(&device, &shader, &rt_layouts, canvas));
+    let mut consts = Consts {
+      size: Vec2::new(width as _, height as _),
+      rand: rand::random(),
+      samples: 1,
+      cam_pos: Vec3::ZERO,
+      zero: 0.0,
+      probe: Vec2::ZERO,
+      cam_yaw: 0.0,
+      cam_pitch: 0.0,
+      cam_fov: 0.0,
+      cam_aperture: 0.0,
+      cam_focus: 0.0,
+      cam_projection: 0,
+      cam_blades: 0,
+      cam_blade_rotation: 0.0,
+      bokeh_size,
+      cam_pos_end: Vec3::ZERO,
+      cam_yaw_end: 0.0,
+      cam_pitch_end: 0.0,
+      shutter_open: scene.shutter.0,
+      shutter_close: scene.shutter.1,
+      padding: 0.0,
+      window: Vec2::new(width as _, height as _),
+      view_offset: Vec2::ZERO,
+      tile_offset: Vec2::ZERO,
+      tile_size: canvas.as_vec2(),
+      view_scale: 1.0,
+      batch: 1,
+      noise: 0.0,
+      aov: Aov::Image as u32,
+      cam_ortho_size: 0.0,
+    };
+    scene.camera.write(&mut consts);
+    let tiles = (0..height)
+      .step_by(canvas.y as _)
+      .flat_map(|y| {
+        (0..width)
+          .step_by(canvas.x as _)
+          .map(move |x| UVec2::new(x, y))
+      })
+      .collect();
+    Ok(Self {
+      device,
+      queue,
+      shader,
+      layouts,
+      uniform_bind_group,
+      sampler_bind_group,
+      scene_bind_group,
+      mask_bind_group,
+      textures,
+      image: None,
+      denoiser: None,
+      aov_view,
+      assets,
+      min,
+      max,
+      consts,
+      tiles,
+      tile: 0,
+      rt_pipeline,
+      wavefront,
+      uniform_buf,
+      sphere_buf,
+      instance_buf,
+      material_buf,
+      sky_bind_group,
+      bokeh_bind_group,
+    })
+  }
+
+  /// Uploads the current poses and materials from `scene` and starts
+  /// accumulating again.
+  pub fn set_scene(&mut self, scene: &Scene) {
+    self
+      .queue
+      .write_buffer(&self.sphere_buf, 0, cast_slice(&scene.spheres));
+    self
+      .queue
+      .write_buffer(&self.instance_buf, 0, cast(&scene.mesh));
+    self
+      .queue
+      .write_buffer(&self.material_buf, 0, cast_slice(&scene.materials));
+    (self.min, self.max) = bounds(scene);
+    scene.camera.write(&mut self.consts);
+    self.consts.shutter_open = scene.shutter.0;
+    self.consts.shutter_close = scene.shutter.1;
+    self.restart();
+  }
+
+  /// Records `consts.batch` more samples per pixel into `encoder`. `consts`
+  /// has to be uploaded with `write_consts` before the encoder is submitted.
+  pub fn sample(&mut self, encoder: &mut wgpu::CommandEncoder) {
+    let bind_groups = [
+      &self.uniform_bind_group,
+      &self.sampler_bind_group,
+      &self.textures.storage_bind_group,
+      &self.sky_bind_group,
+      &self.scene_bind_group,
+      &self.mask_bind_group,
+      &self.bokeh_bind_group,
+    ];
+    // only the part of the tile inside the image is dispatched
+    let size = self.consts.tile_size.as_uvec2();
+    if let Some(wavefront) = &self.wavefront {
+      let (batch, clear) = (self.consts.batch, self.consts.samples == 1);
+      wavefront.sample(encoder, &bind_groups, size, batch, clear);
+      return;
+    }
+    let mut rt_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+    rt_pass.set_pipeline(&self.rt_pipeline);
+    for (i, bind_group) in bind_groups.iter().enumerate() {
+      rt_pass.set_bind_group(i as _, bind_group, &[]);
+    }
+    let groups = (size + WORKGROUP - 1) / WORKGROUP;
+    rt_pass.dispatch_workgroups(groups.x, groups.y, 1);
+  }
+
+  /// Records filtering the noise out of the current tile into
+  /// `denoiser.bind_group`, if `keep_denoiser` has set it up.
+  pub fn denoise(&self, encoder: &mut wgpu::CommandEncoder) {
+    let size = self.consts.tile_size.as_uvec2();
+    if let Some(denoiser) = &self.denoiser {
+      denoiser.denoise(encoder, &self.uniform_bind_group, size);
+    }
+  }
+
+  /// Records writing the AOV picked by `consts.aov` for the current tile into
+  /// `aov_view.bind_group`.
+  pub fn show_aov(&self, encoder: &mut wgpu::CommandEncoder) {
+    let size = self.consts.tile_size.as_uvec2();
+    self.aov_view.show(encoder, &self.uniform_bind_group, size);
+  }
+
+  pub fn write_consts(&self) {
+    self
+      .queue
+      .write_buffer(&self.uniform_buf, 0, cast(&self.consts));
+  }
+
+  /// Moves on to the next samples after the ones just recorded.
+  pub fn advance(&mut self) {
+    self.consts.rand = rand::random();
+    self.consts.samples += self.consts.batch;
+  }
+
+  /// Most samples per pixel `sample` takes in one go, which only the
+  /// wavefront integrator limits.
+  pub fn max_batch(&self) -> u32 {
+    match self.wavefront {
+      Some(_) => Wavefront::max_batch(),
+      None => u32::MAX,
+    }
+  }
+
+  /// Number of samples per pixel accumulated so far.
+  pub fn samples(&self) -> u32 {
+    self.consts.samples - 1
+  }
+
+  /// Size of what is rendered at once, the tile size or the whole image.
+  pub fn canvas(&self) -> UVec2 {
+    let size = self.textures.sums.size();
+    UVec2::new(size.width, size.height)
+  }
+
+  /// Top left corners of the tiles covering the image, row by row. Just the
+  /// one without tiling.
+  pub fn tiles(&self) -> &[UVec2] {
+    &self.tiles
+  }
+
+  /// Index of the tile being rendered.
+  pub fn tile(&self) -> usize {
+    self.tile
+  }
+
+  /// Starts accumulating the `i`th tile from scratch.
+  pub fn set_tile(&mut self, i: usize) {
+    self.tile = i;
+    self.consts.tile_offset = self.tiles[i].as_vec2();
+    self.consts.tile_size = self
+      .canvas()
+      .min(self.consts.size.as_uvec2() - self.tiles[i])
+      .as_vec2();
+    self.consts.samples = 1;
+  }
+
+  /// Starts accumulating again from the first tile, after the view changed.
+  /// Tiles of the old view are cleared out of `image`.
+  pub fn restart(&mut self) {
+    self.set_tile(0);
+    let Some((image, _)) = &self.image else {
+      return;
+    };
+    let view = image.create_view(&wgpu::TextureViewDescriptor::default());
+    let mut encoder = self
+      .device
+      .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+    encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+      color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+        view: &view,
+        resolve_target: None,
+        ops: wgpu::Operations {
+          load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+          store: true,
+        },
+      })],
+      depth_stencil_attachment: None,
+      label: None,
+    });
+    self.queue.submit([encoder.finish()]);
+  }
+
+  /// Sets up `denoiser` unless it already is, so its textures are only
+  /// allocated once something is denoised.
+  pub fn keep_denoiser(&mut self) {
+    if self.denoiser.is_none() {
+      self.denoiser = Some(Denoiser::new(
+        &self.device,
+        &self.shader,
+        &self.layouts.uniform,
+        &self.layouts.texture,
+        self.textures.accumulators(),
+        self.canvas(),
+      ));
+    }
+  }
+
+  /// Sets up `image` for assembling the tiles of the whole image on the GPU.
+  pub fn keep_image(&mut self) {
+    let size = self.consts.size.as_uvec2();
+    let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+      size: wgpu::Extent3d {
+        width: size.x,
+        height: size.y,
+        depth_or_array_layers: 1,
+      },
+      mip_level_count: 1,
+      sample_count: 1,
+      dimension: wgpu::TextureDimension::D2,
+      format: wgpu::TextureFormat::Rgba32Float,
+      usage: wgpu::TextureUsages::COPY_DST
+        | wgpu::TextureUsages::COPY_SRC
+        | wgpu::TextureUsages::TEXTURE_BINDING
+        | wgpu::TextureUsages::RENDER_ATTACHMENT,
+      view_formats: &[],
+      label: None,
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+      layout: &self.layouts.texture,
+      entries: &[wgpu::BindGroupEntry {
+        binding: 0,
+        resource: wgpu::BindingResource::TextureView(&view),
+      }],
+      label: None,
+    });
+    self.image = Some((texture, bind_group));
+  }
+
+  /// Part of the canvas that lies inside the image, at the current tile.
+  fn tile_extent(&self) -> wgpu::Extent3d {
+    let size = self.consts.tile_size.as_uvec2();
+    wgpu::Extent3d {
+      width: size.x,
+      height: size.y,
+      depth_or_array_layers: 1,
+    }
+  }
+
+  /// Records copying the current tile into its place in `image`, if it's kept.
+  pub fn copy_tile(&self, encoder: &mut wgpu::CommandEncoder) {
+    let Some((image, _)) = &self.image else {
+      return;
+    };
+    let origin = self.consts.tile_offset.as_uvec2();
+    encoder.copy_texture_to_texture(
+      wgpu::ImageCopyTexture {
+        texture: &self.textures.sums,
+        mip_level: 0,
+        origin: wgpu::Origin3d::default(),
+        aspect: wgpu::TextureAspect::All,
+      },
+      wgpu::ImageCopyTexture {
+        texture: image,
+        mip_level: 0,
+        origin: wgpu::Origin3d {
+          x: origin.x,
+          y: origin.y,
+          z: 0,
+        },
+        aspect: wgpu::TextureAspect::All,
+      },
+      self.tile_extent(),
+    );
+  }
+
+  /// Copies the current tile back from the GPU into its place in `image` and
+  /// `aovs`, the sums of the whole image laid out like `read_raw` and
+  /// `read_aovs`.
+  pub fn read_tile(&self, image: &mut [f32], aovs: &mut [f32]) {
+    let extent = self.consts.tile_size.as_uvec2();
+    let origin = self.consts.tile_offset.as_uvec2();
+    let size = self.consts.size.as_uvec2();
+    place_tile(&self.read_raw(), self.canvas(), extent, image, size, origin);
+    place_tile(&self.read_aovs(), self.canvas(), extent, aovs, size, origin);
+  }
+
+  /// Copies the accumulated sums back from the GPU, four floats per pixel.
+  pub fn read_raw(&self) -> Vec<f32> {
+    self.read_texture(&self.textures.sums)
+  }
+
+  /// Copies the sums of the AOVs back from the GPU, one layer after another
+  /// of four floats per pixel.
+  pub fn read_aovs(&self) -> Vec<f32> {
+    self.read_texture(&self.textures.aovs)
+  }
+
+  /// Copies the sums of squared luminance back from the GPU, one float per
+  /// pixel.
+  pub fn read_moments(&self) -> Vec<f32> {
+    self.read_texture(&self.textures.moments)
+  }
+
+  /// Estimates the mean relative error of the pixels in the current tile from
+  /// the variance of their samples, the same way the path tracer decides
+  /// they have converged. Infinite while some pixel has fewer than two, or
+  /// has only ever come out the same and has fewer than `FLAT_SAMPLES`.
+  pub fn error(&self) -> f32 {
+    let (sums, moments) = (self.read_raw(), self.read_moments());
+    let width = self.canvas().x as usize;
+    let size = self.consts.tile_size.as_uvec2();
+    let mut total = 0.0;
+    for y in 0..size.y as usize {
+      for x in 0..size.x as usize {
+        let i = y * width + x;
+        let n = sums[i * 4 + 3];
+        if n < 2.0 {
+          return f32::INFINITY;
+        }
+        let mean = luminance(Vec3::from_slice(&sums[i * 4..])) / n;
+        let variance = (moments[i] / n - mean * mean).max(0.0);
+        if variance <= 0.0 && n < FLAT_SAMPLES {
+          return f32::INFINITY;
+        }
+        total += (variance / (n - 1.0)).sqrt() / mean.max(0.01);
+      }
+    }
+    total / (size.x * size.y) as f32
+  }
+
+  /// Mean samples per pixel taken in the current tile, behind `samples` by
+  /// those skipped in converged pixels. `None` without a noise threshold, as
+  /// every pixel then takes every sample.
+  pub fn taken(&self) -> Option<f32> {
+    if self.consts.noise <= 0.0 {
+      return None;
+    }
+    let sums = self.read_raw();
+    let width = self.canvas().x as usize;
+    let size = self.consts.tile_size.as_uvec2();
+    let mut total = 0.0;
+    for y in 0..size.y as usize {
+      for x in 0..size.x as usize {
+        total += sums[(y * width + x) * 4 + 3] as f64;
+      }
+    }
+    Some((total / (size.x * size.y) as f64) as f32)
+  }
+
+  /// Copies a texture of floats back from the GPU, one layer after another.
+  pub fn read_texture(&self, texture: &wgpu::Texture) -> Vec<f32> {
+    let size = texture.size();
+    let px_size = texture.format().block_size(None).unwrap();
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    let row = (size.width * px_size + align - 1) / align * align;
+    let rows = size.height * size.depth_or_array_layers;
+    let buf = self.device.create_buffer(&wgpu::BufferDescriptor {
+      size: (row * rows) as _,
+      usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+      mapped_at_creation: false,
+      label: None,
+    });
+    let mut encoder = self
+      .device
+      .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+    encoder.copy_texture_to_buffer(
+      wgpu::ImageCopyTexture {
+        texture,
+        mip_level: 0,
+        origin: wgpu::Origin3d::default(),
+        aspect: wgpu::TextureAspect::All,
+      },
+      wgpu::ImageCopyBuffer {
+        buffer: &buf,
+        layout: wgpu::ImageDataLayout {
+          offset: 0,
+          bytes_per_row: Some(row),
+          rows_per_image: Some(size.height),
+        },
+      },
+      size,
+    );
+    self.queue.submit([encoder.finish()]);
+    let slice = buf.slice(..);
+    slice.map_async(wgpu::MapMode::Read, |_| {});
+    self.device.poll(wgpu::Maintain::Wait);
+    let mut data = Vec::with_capacity((size.width * rows * px_size / 4) as _);
+    for line in slice.get_mapped_range().chunks(row as _) {
+      for v in line[..(size.width * px_size) as _].chunks(4) {
+        data.push(f32::from_ne_bytes(v.try_into().unwrap()));
+      }
+    }
+    buf.unmap();
+    data
+  }
+
+  /// Replaces the contents of a texture of floats with `data`, laid out like
+  /// `read_texture` returns it.
+  pub fn write_texture(&self, texture: &wgpu::Texture, data: &[f32]) {
+    let size = texture.size();
+    self.queue.write_texture(
+      wgpu::ImageCopyTexture {
+        texture,
+        mip_level: 0,
+        origin: wgpu::Origin3d::default(),
+        aspect: wgpu::TextureAspect::All,
+      },
+      cast_slice(data),
+      wgpu::ImageDataLayout {
+        offset: 0,
+        bytes_per_row: Some(size.width * texture.format().block_size(None).unwrap()),
+        rows_per_image: Some(size.height),
+      },
+      size,
+    );
+  }
+}
+
+/// Turns accumulated sums into an image, dividing each pixel by the number of
+/// samples kept in its alpha.
+pub fn average(mut sums: Vec<f32>, size: UVec2) -> image::Rgba32FImage {
+  for px in sums.chunks_mut(4) {
+    let samples = px[3].max(1.0);
+    for c in &mut px[..3] {
+      *c /= samples;
+    }
+    px[3] = 1.0;
+  }
+  image::Rgba32FImage::from_raw(size.x, size.y, sums).unwrap()
+}
+
+/// Writes a rendered image to `path`. OpenEXR files keep the linear values,
+/// anything else is tonemapped like the window with `depth` bits per channel.
+pub fn save(image: &image::Rgba32FImage, path: &str, depth: u32) -> Result {
+  let ext = Path::new(path).extension().unwrap_or_default();
+  if ext.eq_ignore_ascii_case("exr") {
+    return Ok(image.save(path)?);
+  }
+  let mapped = image::Rgba32FImage::from_fn(image.width(), image.height(), |x, y| {
+    let [r, g, b, a] = image.get_pixel(x, y).0;
+    let c = unreal(Vec3::new(r, g, b)).clamp(Vec3::ZERO, Vec3::ONE);
+    image::Rgba([c.x, c.y, c.z, a])
+  });
+  let mapped = image::DynamicImage::ImageRgba32F(mapped);
+  match depth {
+    8 => mapped.to_rgba8().save(path)?,
+    16 => mapped.to_rgba16().save(path)?,
+    _ => return Err(format!("can't write {} bits per channel", depth).into()),
+  }
+  Ok(())
+}
+
+/// Bind group layouts shared by the path tracer and the passes drawing to the
+/// window, so they can use the same bind groups.
+pub struct Layouts {
+  pub uniform: wgpu::BindGroupLayout,
+  pub sampler: wgpu::BindGroupLayout,
+  /// A sampled `Rgba32Float` texture.
+  pub texture: wgpu::BindGroupLayout,
+  pub scene: wgpu::BindGroupLayout,
+  pub masks: wgpu::BindGroupLayout,
+  storage: wgpu::BindGroupLayout,
+  bokeh: wgpu::BindGroupLayout,
+}
+
+impl Layouts {
+  fn new(device: &wgpu::Device) -> Self {
+    let storage_buffer = wgpu::BindingType::Buffer {
+      ty: wgpu::BufferBindingType::Storage { read_only: false },
+      has_dynamic_offset: false,
+      min_binding_size: None,
+    };
+    let stages = wgpu::ShaderStages::FRAGMENT | wgpu::ShaderStages::COMPUTE;
+    Self {
+      uniform: layout(
+        device,
+        wgpu::ShaderStages::all(),
+        &[wgpu::BindingType::Buffer {
+          ty: wgpu::BufferBindingType::Uniform,
+          has_dynamic_offset: false,
+          min_binding_size: None,
+        }],
+      ),
+      sampler: layout(
+        device,
+        stages,
+        &[wgpu::BindingType::Sampler(
+          wgpu::SamplerBindingType::Filtering,
+        )],
+      ),
+      texture: layout(
+        device,
+        stages,
+        &[wgpu::BindingType::Texture {
+          sample_type: wgpu::TextureSampleType::Float { filterable: true },
+          view_dimension: wgpu::TextureViewDimension::D2,
+          multisampled: false,
+        }],
+      ),
+      scene: layout(device, stages, &[storage_buffer; 5]),
+      masks: layout(
+        device,
+        stages,
+        &[wgpu::BindingType::Texture {
+          sample_type: wgpu::TextureSampleType::Float { filterable: true },
+          view_dimension: wgpu::TextureViewDimension::D2Array,
+          multisampled: false,
+        }],
+      ),
+      storage: layout(
+        device,
+        wgpu::ShaderStages::COMPUTE,
+        &[
+          (
+            wgpu::TextureFormat::Rgba32Float,
+            wgpu::TextureViewDimension::D2,
+          ),
+          (
+            wgpu::TextureFormat::R32Float,
+            wgpu::TextureViewDimension::D2,
+          ),
+          (
+            wgpu::TextureFormat::Rgba32Float,
+            wgpu::TextureViewDimension::D2Array,
+          ),
+        ]
+        .map(
+          |(format, view_dimension)| wgpu::BindingType::StorageTexture {
+            access: wgpu::StorageTextureAccess::ReadWrite,
+            format,
+            view_dimension,
+          },
+        ),
+      ),
+      bokeh: layout(device, stages, &[storage_buffer]),
+    }
+  }
+}
+
+/// Layout with one binding per entry of `types`, numbered from 0.
+pub fn layout(
+  device: &wgpu::Device,
+  visibility: wgpu::ShaderStages,
+  types: &[wgpu::BindingType],
+) -> wgpu::BindGroupLayout {
+  let entries = types
+    .iter()
+    .enumerate()
+    .map(|(i, &ty)| wgpu::BindGroupLayoutEntry {
+      binding: i as _,
+      visibility,
+      ty,
+      count: None,
+    })
+    .collect::<Vec<_>>();
+  device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+    entries: &entries,
+    label: None,
+  })
+}
+
+pub struct Textures {
+  sums: wgpu::Texture,
+  /// Sums of the squared luminance of the samples, to tell when a pixel has
+  /// converged.
+  moments: wgpu::Texture,
+  /// Sums of the AOVs, one per layer.
+  aovs: wgpu::Texture,
+  /// The accumulated samples, summed rather than averaged.
+  pub bind_group: wgpu::BindGroup,
+  /// The sums, moments and AOVs for the path tracer to add to in place.
+  storage_bind_group: wgpu::BindGroup,
+}
+
+impl Textures {
+  fn new(device: &wgpu::Device, layouts: &Layouts, width: u32, height: u32) -> Self {
+    let texture = |format, layers| {
+      device.create_texture(&wgpu::TextureDescriptor {
+        size: wgpu::Extent3d {
+          width,
+          height,
+          depth_or_array_layers: layers,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::STORAGE_BINDING
+          | wgpu::TextureUsages::TEXTURE_BINDING
+          | wgpu::TextureUsages::COPY_DST
+          | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[],
+        label: None,
+      })
+    };
+    let sums = texture(wgpu::TextureFormat::Rgba32Float, 1);
+    let moments = texture(wgpu::TextureFormat::R32Float, 1);
+    let aovs = texture(wgpu::TextureFormat::Rgba32Float, AOV_LAYERS);
+    // the view of the AOVs defaults to an array of their layers
+    let views =
+      [&sums, &moments, &aovs].map(|t| t.create_view(&wgpu::TextureViewDescriptor::default()));
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+      layout: &layouts.texture,
+      entries: &[wgpu::BindGroupEntry {
+        binding: 0,
+        resource: wgpu::BindingResource::TextureView(&views[0]),
+      }],
+      label: None,
+    });
+    let storage_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+      layout: &layouts.storage,
+      entries: &[0, 1, 2].map(|i| wgpu::BindGroupEntry {
+        binding: i,
+        resource: wgpu::BindingResource::TextureView(&views[i as usize]),
+      }),
+      label: None,
+    });
+    Self {
+      sums,
+      moments,
+      aovs,
+      bind_group,
+      storage_bind_group,
+    }
+  }
+
+  /// Everything the path tracer accumulates: the sums, moments and AOVs.
+  pub fn accumulators(&self) -> [&wgpu::Texture; 3] {
+    [&self.sums, &self.moments, &self.aovs]
+  }
+}
+
+/// Tangent of a triangle along the u axis of its texture coordinates, or zero
+/// if they don't span the triangle.
+fn face_tangent(pos: [Vec3; 3], uv: [Vec2; 3]) -> Vec3 {
+  let (e1, e2) = (pos[1] - pos[0], pos[2] - pos[0]);
+  let (d1, d2) = (uv[1] - uv[0], uv[2] - uv[0]);
+  let det = d1.x * d2.y - d2.x * d1.y;
+  if det.abs() < 1e-12 {
+    return Vec3::ZERO;
+  }
+  ((e1 * d2.y - e2 * d1.y) / det).normalize_or_zero()
+}
+
+/// Loads the alpha masks as layers of one texture array, scaled to the size of
+/// the first. Without any masks a single opaque layer keeps the binding valid.
+/// Copies each layer of `tile`, four floats per pixel of a `canvas`, into the
+/// same layer of `image`, the sums of `size` pixels. The `extent` pixels of the
+/// tile inside the image go to `origin`.
+fn place_tile(
+  tile: &[f32],
+  canvas: UVec2,
+  extent: UVec2,
+  image: &mut [f32],
+  size: UVec2,
+  origin: UVec2,
+) {
+  let width = size.x as usize;
+  let tile_layer = (canvas.x * canvas.y) as usize * 4;
+  let image_layer = width * size.y as usize * 4;
+  for layer in 0..tile.len() / tile_layer {
+    for y in 0..extent.y as usize {
+      let src = layer * tile_layer + y * canvas.x as usize * 4;
+      let dst = layer * image_layer + ((origin.y as usize + y) * width + origin.x as usize) * 4;
+      let len = extent.x as usize * 4;
+      image[dst..dst + len].copy_from_slice(&tile[src..src + len]);
+    }
+  }
+}
+
+/// Box around the mesh instance and the spheres, at both ends of the frame.
+fn bounds(scene: &Scene) -> (Vec3, Vec3) {
+  let (mut min, mut max) = (Vec3::MAX, Vec3::MIN);
+  let mesh = &scene.mesh;
+  if mesh.min.cmple(mesh.max).all() {
+    for (rot, pos) in [(mesh.rot, mesh.pos), (mesh.rot_end, mesh.pos_end)] {
+      for i in 0..8 {
+        let corner = BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0);
+        let corner = rot * Vec3::select(corner, mesh.max, mesh.min) + pos;
+        min = min.min(corner);
+        max = max.max(corner);
+      }
+    }
+  }
+  for sphere in &scene.spheres {
+    for pos in [sphere.pos, sphere.pos_end] {
+      min = min.min(pos - sphere.radius.abs());
+      max = max.max(pos + sphere.radius.abs());
+    }
+  }
+  (min, max)
+}
+
+fn load_masks(paths: &[String]) -> Result<Vec<image::RgbaImage>> {
+  let mut masks: Vec<image::RgbaImage> = vec![];
+  for path in paths {
+    let mut mask = image::open(path)?.to_rgba8();
+    if let Some(first) = masks.first() {
+      if first.dimensions() != mask.dimensions() {
+        mask = image::imageops::resize(
+          &mask,
+          first.width(),
+          first.height(),
+          image::imageops::FilterType::Triangle,
+        );
+      }
+    }
+    masks.push(mask);
+  }
+  if masks.is_empty() {
+    masks.push(image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4])));
+  }
+  Ok(masks)
+}
+
+/// Builds the CDFs for importance sampling an aperture mask by brightness:
+/// first one over the rows, then one over the pixels of each row.
+fn bokeh_cdf(path: &str) -> Result<(UVec2, Vec<f32>)> {
+  let mask = image::open(path)?.to_luma32f();
+  let (width, height) = (mask.width() as usize, mask.height() as usize);
+  let mut rows = Vec::with_capacity(height);
+  let mut cdf = vec![0.0; height + width * height];
+  for (y, row) in mask.as_raw().chunks(width).enumerate() {
+    let sum = row.iter().sum::<f32>();
+    let mut acc = 0.0;
+    for (x, px) in row.iter().enumerate() {
+      acc += if sum > 0.0 {
+        px / sum
+      } else {
+        1.0 / width as f32
+      };
+      cdf[height + y * width + x] = acc;
+    }
+    rows.push(sum);
+  }
+  let total = rows.iter().sum::<f32>();
+  if total <= 0.0 {
+    return Err(format!("aperture mask {} is black", path).into());
+  }
+  let mut acc = 0.0;
+  for (y, sum) in rows.iter().enumerate() {
+    acc += sum / total;
+    cdf[y] = acc;
+  }
+  Ok((UVec2::new(width as _, height as _), cdf))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Four floats per pixel of a `size` image with `layers`, each holding the
+  /// pixel's index.
+  fn numbered(size: UVec2, layers: u32) -> Vec<f32> {
+    (0..size.x * size.y * layers)
+      .flat_map(|i| [i as f32; 4])
+      .collect()
+  }
+
+  #[test]
+  fn place_tile_fills_its_part_of_the_image() {
+    let (canvas, size) = (UVec2::new(4, 4), UVec2::new(6, 6));
+    let mut image = vec![-1.0; 6 * 6 * 4];
+    place_tile(
+      &numbered(canvas, 1),
+      canvas,
+      canvas,
+      &mut image,
+      size,
+      UVec2::ZERO,
+    );
+    assert_eq!(image[0], 0.0);
+    assert_eq!(image[(6 + 3) * 4], 7.0);
+    assert_eq!(image[(3 * 6 + 3) * 4], 15.0);
+    assert_eq!(image[4 * 4], -1.0);
+    assert_eq!(image[4 * 6 * 4], -1.0);
+  }
+
+  #[test]
+  fn place_tile_clips_at_the_right_and_bottom_edges() {
+    let (canvas, size) = (UVec2::new(4, 4), UVec2::new(6, 6));
+    let extent = UVec2::new(2, 2);
+    let mut image = vec![-1.0; 2 * 6 * 6 * 4];
+    let tile = numbered(canvas, 2);
+    place_tile(&tile, canvas, extent, &mut image, size, UVec2::new(4, 4));
+    let layer = 6 * 6 * 4;
+    for layer_start in [0, layer] {
+      let first = if layer_start == 0 { 0.0 } else { 16.0 };
+      assert_eq!(image[layer_start + (4 * 6 + 4) * 4], first);
+      assert_eq!(image[layer_start + (4 * 6 + 5) * 4], first + 1.0);
+      assert_eq!(image[layer_start + (5 * 6 + 4) * 4], first + 4.0);
+      assert_eq!(image[layer_start + (5 * 6 + 5) * 4 + 3], first + 5.0);
+      assert_eq!(image[layer_start + (4 * 6 + 3) * 4], -1.0);
+      assert_eq!(image[layer_start + (3 * 6 + 4) * 4], -1.0);
+    }
+  }
+}
@@ -0,0 +1,374 @@
+use std::fs;
+use std::io::ErrorKind;
+use glam::{Quat, Vec3};
+use shared::{Instance, Material, Projection, Sphere, NO_TEXTURE};
+use crate::camera::Camera;
+use crate::Result;
+
+/// Scene settings, read from a text file with one `key value...` setting per
+/// line. Blank lines and lines starting with `#` are skipped, and anything not
+/// set keeps its default.
+///
+/// ```text
+/// camera.pos 0 1.5 0
+/// camera.fov 0.6
+/// camera.projection fisheye
+/// camera.bokeh heart.png
+/// shutter 0 0.5
+/// sphere 0 1.5 -10 1.5 2 0 2.5 -10
+/// mask leaves.png
+/// material.8.alpha_texture 0
+/// material.8.alpha_cutoff 0.5
+/// mesh.rot_end 0 1 0 0.2
+/// frames 0 47
+/// @0 mesh.rot 0 1 0 0
+/// @47 mesh.rot 0 1 0 6.2832
+/// ```
+///
+/// `sphere x y z radius material [x y z]` adds a sphere, moving to the second
+/// position over the frame if there is one. The first `sphere` line replaces
+/// the default spheres, which can then be moved with `sphere.<index>.pos`.
+/// `mesh.rot` and `mesh.rot_end` take an axis and an angle in radians. The
+/// `_end` settings give the pose at the end of the frame for motion blur, and
+/// go after the start pose they replace.
+///
+/// Each `mask path` line adds an image to the alpha masks, numbered from 0 in
+/// order. `material.<index>.alpha_texture` cuts a material out with one of
+/// them, and `material.<index>.alpha` and `alpha_cutoff` set its opacity.
+///
+/// A line starting with `@frame` is a keyframe for the setting after it.
+/// Settings with keyframes are interpolated linearly between them, and hold
+/// their first and last values outside of them.
+pub struct Scene {
+  pub camera: Camera,
+  /// Image whose brightness gives the shape of the aperture.
+  pub bokeh: Option<String>,
+  pub spheres: Vec<Sphere>,
+  pub materials: Vec<Material>,
+  /// Images whose alpha cuts out materials, by `Material::alpha_texture`.
+  pub masks: Vec<String>,
+  /// Placement of the loaded mesh. The triangle range and bounds are filled in
+  /// once the mesh is loaded.
+  pub mesh: Instance,
+  /// Start and end of the exposure, as fractions of the frame.
+  pub shutter: (f32, f32),
+  /// First and last frame of the animation.
+  pub frames: (u32, u32),
+  tracks: Vec<Track>,
+}
+
+/// Keyframes of one setting, sorted by frame.
+struct Track {
+  key: String,
+  keys: Vec<(f32, Vec<f32>)>,
+}
+
+impl Track {
+  fn at(&self, frame: f32) -> Vec<f32> {
+    let next = self.keys.partition_point(|(f, _)| *f <= frame);
+    if next == 0 {
+      return self.keys[0].1.clone();
+    }
+    if next == self.keys.len() {
+      return self.keys[next - 1].1.clone();
+    }
+    let (f0, a) = &self.keys[next - 1];
+    let (f1, b) = &self.keys[next];
+    let t = (frame - f0) / (f1 - f0);
+    a.iter().zip(b).map(|(a, b)| a + (b - a) * t).collect()
+  }
+}
+
+fn sphere(pos: Vec3, radius: f32, mat: u32) -> Sphere {
+  Sphere {
+    pos,
+    radius,
+    pos_end: pos,
+    mat,
+  }
+}
+
+impl Scene {
+  /// Loads the scene at `path`, or the defaults if there is no such file.
+  pub fn load(path: &str) -> Result<Self> {
+    let mut scene = Self {
+      camera: Camera::new(Vec3::new(0.0, 1.5, 0.0)),
+      bokeh: None,
+      spheres: vec![
+        sphere(Vec3::new(0.0, -200.0, 0.0), 200.0, 0),
+        sphere(Vec3::new(-3.0, 1.5, -7.5), 1.5, 1),
+        sphere(Vec3::new(0.0, 1.5, -10.0), 1.5, 2),
+        sphere(Vec3::new(3.0, 1.5, -7.5), 1.5, 3),
+        sphere(Vec3::new(0.0, 1.5, 2.5), 1.5, 4),
+        sphere(Vec3::new(1.5, 1.0, -3.0), 0.75, 5),
+        sphere(Vec3::new(-1.5, 1.0, -3.0), 0.75, 6),
+      ],
+      materials: vec![
+        Material::lambertian(Vec3::splat(0.8)),
+        Material::lambertian(Vec3::X),
+        Material::lambertian(Vec3::Y),
+        Material::lambertian(Vec3::Z),
+        Material::lambertian(Vec3::new(1.0, 0.0, 1.0)),
+        Material::dielectric(Vec3::ONE, 1.5),
+        Material::metal(Vec3::splat(0.8), 0.0),
+        Material::emissive(Vec3::ONE, 5.0),
+        Material {
+          base_color: Vec3::new(0.6, 0.05, 0.05),
+          clearcoat: 1.0,
+          clearcoat_roughness: 0.15,
+          ..Material::DEFAULT
+        },
+      ],
+      masks: vec![],
+      mesh: Instance {
+        rot: Quat::IDENTITY,
+        rot_end: Quat::IDENTITY,
+        pos: Vec3::ZERO,
+        mat: 8,
+        pos_end: Vec3::ZERO,
+        start: 0,
+        min: Vec3::ZERO,
+        end: 0,
+        max: Vec3::ZERO,
+      },
+      shutter: (0.0, 0.5),
+      frames: (0, 0),
+      tracks: vec![],
+    };
+    let mut default_spheres = true;
+    let text = match fs::read_to_string(path) {
+      Ok(text) => text,
+      Err(e) if e.kind() == ErrorKind::NotFound => return Ok(scene),
+      Err(e) => return Err(e.into()),
+    };
+    for (i, line) in text.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let mut words = line.split_whitespace();
+      let mut key = words.next().unwrap_or_default();
+      let mut keyframe = None;
+      if let Some(frame) = key.strip_prefix('@') {
+        let frame = frame
+          .parse::<f32>()
+          .map_err(|e| format!("{}:{}: {}", path, i + 1, e))?;
+        keyframe = Some(frame);
+        key = words.next().unwrap_or_default();
+        if matches!(key, "camera.projection" | "camera.bokeh" | "mask") {
+          return Err(format!("{}:{}: `{}` can't be animated", path, i + 1, key).into());
+        }
+      }
+      if key == "camera.projection" {
+        scene.camera.projection = match words.next() {
+          Some("perspective") => Projection::Perspective,
+          Some("orthographic") => Projection::Orthographic,
+          Some("equirect") => Projection::Equirect,
+          Some("fisheye") => Projection::Fisheye,
+          _ => return Err(format!("{}:{}: unknown projection", path, i + 1).into()),
+        };
+        continue;
+      }
+      if key == "camera.bokeh" {
+        scene.bokeh = words.next().map(String::from);
+        continue;
+      }
+      if key == "mask" {
+        let Some(mask) = words.next() else {
+          return Err(format!("{}:{}: missing mask path", path, i + 1).into());
+        };
+        scene.masks.push(mask.into());
+        continue;
+      }
+      let values = words
+        .map(str::parse)
+        .collect::<std::result::Result<Vec<f32>, _>>()
+        .map_err(|e| format!("{}:{}: {}", path, i + 1, e))?;
+      if let Some(frame) = keyframe {
+        if !scene.set(key, &values) {
+          return Err(format!("{}:{}: `{}` can't be animated", path, i + 1, key).into());
+        }
+        let track = match scene.tracks.iter().position(|t| t.key == key) {
+          Some(t) => &mut scene.tracks[t],
+          None => {
+            scene.tracks.push(Track {
+              key: key.into(),
+              keys: vec![],
+            });
+            scene.tracks.last_mut().unwrap()
+          }
+        };
+        if track
+          .keys
+          .first()
+          .is_some_and(|(_, v)| v.len() != values.len())
+        {
+          return Err(format!("{}:{}: wrong number of values", path, i + 1).into());
+        }
+        let at = track.keys.partition_point(|(f, _)| *f <= frame);
+        track.keys.insert(at, (frame, values));
+        continue;
+      }
+      match (key, values.as_slice()) {
+        ("frames", &[first, last]) => scene.frames = (first as u32, last as u32),
+        ("sphere", &[x, y, z, r, mat, ref end @ ..]) if matches!(end.len(), 0 | 3) => {
+          if std::mem::take(&mut default_spheres) {
+            scene.spheres.clear();
+          }
+          let mut s = sphere(Vec3::new(x, y, z), r, mat as u32);
+          if let &[x, y, z] = end {
+            s.pos_end = Vec3::new(x, y, z);
+          }
+          scene.spheres.push(s);
+        }
+        _ if scene.set(key, &values) => {}
+        _ => return Err(format!("{}:{}: invalid setting `{}`", path, i + 1, line).into()),
+      }
+    }
+    let masks = scene.masks.len() as u32;
+    let cut = |m: &Material| m.alpha_texture != NO_TEXTURE && m.alpha_texture >= masks;
+    if let Some(m) = scene.materials.iter().position(cut) {
+      return Err(format!("{}: material {} has no such mask", path, m).into());
+    }
+    if !scene.tracks.is_empty() {
+      scene.animate(scene.frames.0 as f32);
+    }
+    Ok(scene)
+  }
+
+  /// Applies one numeric setting, returning whether it is a valid one.
+  fn set(&mut self, key: &str, values: &[f32]) -> bool {
+    let cam = &mut self.camera;
+    match (key, values) {
+      ("camera.pos", &[x, y, z]) => cam.pos = Vec3::new(x, y, z),
+      ("camera.yaw", &[v]) => cam.yaw = v,
+      ("camera.pitch", &[v]) => cam.pitch = v,
+      ("camera.fov", &[v]) => cam.fov = v,
+      ("camera.aperture", &[v]) => cam.aperture = v,
+      ("camera.focus", &[v]) => cam.focus = v,
+      ("camera.ortho_size", &[v]) => cam.ortho_size = v,
+      ("camera.blades", &[v]) => cam.blades = v as u32,
+      ("camera.blade_rotation", &[v]) => cam.blade_rotation = v,
+      ("camera.pos_end", &[x, y, z]) => cam.pos_end = Some(Vec3::new(x, y, z)),
+      ("camera.yaw_end", &[v]) => cam.yaw_end = Some(v),
+      ("camera.pitch_end", &[v]) => cam.pitch_end = Some(v),
+      ("shutter", &[open, close]) => self.shutter = (open, close),
+      ("mesh.pos", &[x, y, z]) => {
+        self.mesh.pos = Vec3::new(x, y, z);
+        self.mesh.pos_end = self.mesh.pos;
+      }
+      ("mesh.pos_end", &[x, y, z]) => self.mesh.pos_end = Vec3::new(x, y, z),
+      ("mesh.rot", &[x, y, z, a]) => {
+        self.mesh.rot = axis_angle(x, y, z, a);
+        self.mesh.rot_end = self.mesh.rot;
+      }
+      ("mesh.rot_end", &[x, y, z, a]) => self.mesh.rot_end = axis_angle(x, y, z, a),
+      ("mesh.mat", &[v]) => self.mesh.mat = v as u32,
+      (_, &[x, y, z]) if key.starts_with("sphere.") => {
+        let Some((i, field)) = key["sphere.".len()..].split_once('.') else {
+          return false;
+        };
+        let Some(s) = i.parse().ok().and_then(|i: usize| self.spheres.get_mut(i)) else {
+          return false;
+        };
+        match field {
+          "pos" => {
+            s.pos = Vec3::new(x, y, z);
+            s.pos_end = s.pos;
+          }
+          "pos_end" => s.pos_end = Vec3::new(x, y, z),
+          _ => return false,
+        }
+      }
+      (_, &[v]) if key.starts_with("material.") => {
+        let Some((i, field)) = key["material.".len()..].split_once('.') else {
+          return false;
+        };
+        let Some(m) = i
+          .parse()
+          .ok()
+          .and_then(|i: usize| self.materials.get_mut(i))
+        else {
+          return false;
+        };
+        match field {
+          "alpha" => m.alpha = v,
+          "alpha_cutoff" => m.alpha_cutoff = v,
+          "alpha_texture" => m.alpha_texture = v as u32,
+          _ => return false,
+        }
+      }
+      _ => return false,
+    }
+    true
+  }
+
+  /// Poses everything with keyframes where it is at `frame`, moving to where
+  /// it is a frame later for motion blur.
+  pub fn animate(&mut self, frame: f32) {
+    let tracks = std::mem::take(&mut self.tracks);
+    for track in &tracks {
+      self.set(&track.key, &track.at(frame));
+    }
+    for track in &tracks {
+      self.set(&format!("{}_end", track.key), &track.at(frame + 1.0));
+    }
+    self.tracks = tracks;
+  }
+}
+
+fn axis_angle(x: f32, y: f32, z: f32, angle: f32) -> Quat {
+  Quat::from_axis_angle(Vec3::new(x, y, z).normalize(), angle)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Loads a scene from `text`, written to a file of its own.
+  fn load(name: &str, text: &str) -> Result<Scene> {
+    let path = std::env::temp_dir().join(name);
+    fs::write(&path, text)?;
+    let scene = Scene::load(path.to_str().unwrap());
+    fs::remove_file(path)?;
+    scene
+  }
+
+  #[test]
+  fn tracks_hold_their_ends_and_interpolate_between() {
+    let track = Track {
+      key: "camera.fov".into(),
+      keys: vec![(10.0, vec![1.0, 2.0]), (20.0, vec![3.0, 6.0])],
+    };
+    assert_eq!(track.at(0.0), [1.0, 2.0]);
+    assert_eq!(track.at(10.0), [1.0, 2.0]);
+    assert_eq!(track.at(15.0), [2.0, 4.0]);
+    assert_eq!(track.at(20.0), [3.0, 6.0]);
+    assert_eq!(track.at(30.0), [3.0, 6.0]);
+  }
+
+  #[test]
+  fn keyframes_animate_the_scene() {
+    let scene = load(
+      "u-test-keyframes.txt",
+      "frames 0 10\n@0 camera.fov 0.2\n@10 camera.fov 0.6\n",
+    );
+    let mut scene = scene.unwrap();
+    assert_eq!(scene.camera.fov, 0.2);
+    scene.animate(5.0);
+    assert!((scene.camera.fov - 0.4).abs() < 1e-6);
+  }
+
+  #[test]
+  fn settings_without_values_cant_be_animated() {
+    for setting in [
+      "camera.projection orthographic",
+      "camera.bokeh a.png",
+      "mask a.png",
+    ] {
+      let text = format!("@10 {}\n", setting);
+      let err = load("u-test-animated.txt", &text).err().unwrap();
+      assert!(err.to_string().contains("can't be animated"), "{}", err);
+    }
+  }
+}
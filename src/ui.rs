@@ -13,6 +13,8 @@ pub struct Context {
   style: Style,
   input: InputState,
   active_id: Option<u64>,
+  /// Whether the cursor was over a widget last frame.
+  hovered: bool,
   render_state: FrameOutput,
 }
 
@@ -23,6 +25,7 @@ impl Context {
       style: Style::default(),
       input: InputState::default(),
       active_id: None,
+      hovered: false,
       render_state: FrameOutput::default(),
     }
   }
@@ -39,8 +42,15 @@ impl Context {
     &mut self.input
   }
 
+  /// Whether the mouse is over or dragging a widget, so clicks and drags
+  /// shouldn't go to anything underneath.
+  pub fn wants_mouse(&self) -> bool {
+    self.hovered || self.active_id.is_some()
+  }
+
   pub fn begin_frame(&mut self) -> Ui {
     self.render_state = FrameOutput::default();
+    self.hovered = false;
     Ui::new(self)
   }
 
@@ -215,6 +225,7 @@ pub struct Style {
   pub font_size: f32,
   pub button: Vec3,
   pub button_hovered: Vec3,
+  pub slider: Vec3,
 }
 
 impl Style {
@@ -223,6 +234,7 @@ impl Style {
       font_size: 32.0,
       button: Vec3::splat(0.02),
       button_hovered: Vec3::splat(0.05),
+      slider: Vec3::splat(0.15),
     }
   }
 }
@@ -353,6 +365,7 @@ impl<'c> Ui<'c> {
     let min = self.origin + self.cursor;
     let max = min + Vec2::new(self.bounds.x, text.bounds.y);
     let hovered = self.ctx.input.cursor_in(min, max);
+    self.ctx.hovered |= hovered;
     let active = Some(id) == self.ctx.active_id;
     self.ctx.render_state.push_rect_border(
       min,
@@ -381,6 +394,54 @@ impl<'c> Ui<'c> {
     false
   }
 
+  /// Drag to set `value` between `min` and `max`. Returns whether it changed.
+  pub fn slider(&mut self, label: &str, value: &mut f32, min: f32, max: f32) -> bool {
+    self.pre();
+    let id = hash_id(label);
+    let text = Text::new(
+      self.ctx,
+      self.bounds - self.cursor,
+      &format!("{} {:.2}", label, value),
+      self.ctx.style.font_size,
+    );
+    let start = self.origin + self.cursor;
+    let end = start + Vec2::new(self.bounds.x, text.bounds.y);
+    let hovered = self.ctx.input.cursor_in(start, end);
+    self.ctx.hovered |= hovered;
+    if hovered && self.ctx.input.mouse_buttons[0] && self.ctx.active_id.is_none() {
+      self.ctx.active_id = Some(id);
+    }
+    let active = Some(id) == self.ctx.active_id;
+    let mut changed = false;
+    if active {
+      let t = ((self.ctx.input.cursor_pos.x - start.x) / self.bounds.x).clamp(0.0, 1.0);
+      let new = min + t * (max - min);
+      changed = new != *value;
+      *value = new;
+    }
+    let fill = ((*value - min) / (max - min)).clamp(0.0, 1.0) * self.bounds.x;
+    self.ctx.render_state.push_rect(
+      start,
+      end,
+      if hovered || active {
+        self.ctx.style.button_hovered
+      } else {
+        self.ctx.style.button
+      },
+    );
+    self.ctx.render_state.push_rect(
+      start,
+      Vec2::new(start.x + fill, end.y),
+      self.ctx.style.slider,
+    );
+    text.render(
+      self.ctx,
+      start + Vec2::new((self.bounds.x - text.bounds.x) / 2.0, 0.0),
+    );
+    self.last_height = text.bounds.y;
+    changed
+  }
+
   pub fn same_line(&mut self) {
     self.same_line = true;
   }
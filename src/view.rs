@@ -0,0 +1,143 @@
+use winit::event::{ElementState, Event, MouseButton, MouseScrollDelta, WindowEvent};
+use winit::keyboard::{KeyCode, PhysicalKey};
+use glam::Vec2;
+
+const ZOOM: f32 = 1.25;
+
+/// How the render is placed in the window. Fits it by default, and can be
+/// zoomed with the wheel and panned by dragging without touching the render.
+pub struct View {
+  /// Window position of the render's top left corner.
+  pub offset: Vec2,
+  /// Window pixels per render pixel.
+  pub scale: f32,
+  /// Whether to keep the render letterboxed in the window.
+  pub fit: bool,
+  window: Vec2,
+  dragging: bool,
+  cursor: Vec2,
+}
+
+impl View {
+  pub fn new() -> Self {
+    Self {
+      offset: Vec2::ZERO,
+      scale: 1.0,
+      fit: true,
+      window: Vec2::ZERO,
+      dragging: false,
+      cursor: Vec2::ZERO,
+    }
+  }
+
+  /// Places a render of `size` in `window`, returning the offset and scale.
+  pub fn update(&mut self, size: Vec2, window: Vec2) -> (Vec2, f32) {
+    if self.fit {
+      self.scale = (window / size).min_element();
+      self.offset = (window - size * self.scale) / 2.0;
+    }
+    self.window = window;
+    (self.offset, self.scale)
+  }
+
+  /// Render pixel under the window position `pos`.
+  pub fn to_render(&self, pos: Vec2) -> Vec2 {
+    (pos - self.offset) / self.scale
+  }
+
+  /// Scales by `factor` around the window position `at`.
+  pub fn zoom(&mut self, factor: f32, at: Vec2) {
+    let pos = self.to_render(at);
+    self.scale *= factor;
+    self.offset = at - pos * self.scale;
+    self.fit = false;
+  }
+
+  /// Shows one render pixel per window pixel, around the window center.
+  pub fn actual_size(&mut self) {
+    self.zoom(1.0 / self.scale, self.window / 2.0);
+  }
+
+  /// Handles zooming and panning. The wheel only zooms with `wheel` set, and
+  /// dragging only starts with `drag` set. Returns whether the event was used
+  /// up.
+  pub fn handle_event<T>(&mut self, event: &Event<T>, wheel: bool, drag: bool) -> bool {
+    let Event::WindowEvent { event, .. } = event else {
+      return false;
+    };
+    match event {
+      WindowEvent::MouseWheel { delta, .. } if wheel => {
+        let lines = match delta {
+          MouseScrollDelta::LineDelta(_, y) => *y,
+          MouseScrollDelta::PixelDelta(p) => p.y as f32 / 40.0,
+        };
+        self.zoom(ZOOM.powf(lines), self.cursor);
+        return true;
+      }
+      WindowEvent::MouseInput {
+        button: MouseButton::Left,
+        state,
+        ..
+      } => self.dragging = *state == ElementState::Pressed && drag,
+      WindowEvent::CursorMoved { position, .. } => {
+        let pos = Vec2::new(position.x as _, position.y as _);
+        if self.dragging {
+          self.offset += pos - self.cursor;
+          self.fit = false;
+        }
+        self.cursor = pos;
+      }
+      WindowEvent::KeyboardInput { event, .. } if event.state == ElementState::Pressed => {
+        match event.physical_key {
+          PhysicalKey::Code(KeyCode::Digit0) => self.fit = true,
+          PhysicalKey::Code(KeyCode::Digit1) => self.actual_size(),
+          _ => {}
+        }
+      }
+      WindowEvent::Focused(false) => self.dragging = false,
+      _ => {}
+    }
+    false
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn fit_letterboxes_the_render() {
+    let mut view = View::new();
+    let (offset, scale) = view.update(Vec2::new(200.0, 100.0), Vec2::new(400.0, 400.0));
+    assert_eq!(scale, 2.0);
+    assert_eq!(offset, Vec2::new(0.0, 100.0));
+    assert_eq!(
+      view.to_render(Vec2::new(200.0, 200.0)),
+      Vec2::new(100.0, 50.0)
+    );
+  }
+
+  #[test]
+  fn zoom_keeps_the_pixel_under_the_cursor() {
+    let mut view = View::new();
+    view.update(Vec2::new(200.0, 100.0), Vec2::new(400.0, 400.0));
+    let at = Vec2::new(50.0, 150.0);
+    let before = view.to_render(at);
+    view.zoom(ZOOM, at);
+    assert!(!view.fit);
+    assert_eq!(view.scale, 2.0 * ZOOM);
+    assert!(view.to_render(at).abs_diff_eq(before, 1e-4));
+  }
+
+  #[test]
+  fn actual_size_zooms_around_the_center() {
+    let mut view = View::new();
+    view.update(Vec2::new(200.0, 100.0), Vec2::new(400.0, 400.0));
+    let center = view.to_render(Vec2::new(200.0, 200.0));
+    view.actual_size();
+    assert!((view.scale - 1.0).abs() < 1e-6);
+    assert!(view
+      .to_render(Vec2::new(200.0, 200.0))
+      .abs_diff_eq(center, 1e-4));
+  }
+}
@@ -0,0 +1,195 @@
+use std::mem;
+use glam::UVec2;
+use shared::{Path, MAX_BOUNCES, QUEUES, QUEUE_HEADER};
+use crate::renderer::{layout, WORKGROUP};
+
+/// Rounds of bounces `Wavefront::sample` records at most. Each takes a few
+/// passes whether or not any paths are left, so batches are kept small enough
+/// that a dispatch doesn't drown in them.
+pub const MAX_ROUNDS: u32 = 128;
+
+/// The wavefront integrator: instead of one kernel following each path to the
+/// end, separate kernels generate camera rays, trace them, shade each kind of
+/// lobe and trace shadow rays, passing paths along through queues. Keeps the threads of each
+/// kernel on the same code, at the cost of storing every path in between.
+pub struct Wavefront {
+  clear: wgpu::ComputePipeline,
+  generate: wgpu::ComputePipeline,
+  queues: wgpu::ComputePipeline,
+  shadow_queue: wgpu::ComputePipeline,
+  extend: wgpu::ComputePipeline,
+  /// The shading kernels, in the order of their queues after the rays.
+  shade: [wgpu::ComputePipeline; 3],
+  shadow: wgpu::ComputePipeline,
+  bind_group: wgpu::BindGroup,
+  queue_buf: wgpu::Buffer,
+  /// The queue headers, copied out of `queue_buf` to dispatch from, which
+  /// can't be done while the kernels have it bound for writing.
+  args_buf: wgpu::Buffer,
+}
+
+impl Wavefront {
+  /// Sets up the kernels for tiles of up to `canvas` pixels. `layouts` are
+  /// the bind group layouts of the megakernel, to which one more group is
+  /// added for the paths and queues.
+  pub fn new(
+    device: &wgpu::Device,
+    shader: &wgpu::ShaderModule,
+    layouts: &[&wgpu::BindGroupLayout],
+    canvas: UVec2,
+  ) -> Self {
+    let storage_buffer = wgpu::BindingType::Buffer {
+      ty: wgpu::BufferBindingType::Storage { read_only: false },
+      has_dynamic_offset: false,
+      min_binding_size: None,
+    };
+    let wf_layout = layout(device, wgpu::ShaderStages::COMPUTE, &[storage_buffer; 2]);
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+      bind_group_layouts: &[layouts, &[&wf_layout]].concat(),
+      push_constant_ranges: &[],
+      label: None,
+    });
+    let pipeline = |entry_point| {
+      device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        layout: Some(&pipeline_layout),
+        module: shader,
+        entry_point,
+        label: None,
+      })
+    };
+
+    let pixels = (canvas.x * canvas.y) as usize;
+    let path_buf = device.create_buffer(&wgpu::BufferDescriptor {
+      size: (pixels * mem::size_of::<Path>()) as _,
+      usage: wgpu::BufferUsages::STORAGE,
+      mapped_at_creation: false,
+      label: None,
+    });
+    let headers = (QUEUES * QUEUE_HEADER * mem::size_of::<u32>()) as u64;
+    let queue_buf = device.create_buffer(&wgpu::BufferDescriptor {
+      size: headers + (QUEUES * pixels * mem::size_of::<u32>()) as u64,
+      usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+      mapped_at_creation: false,
+      label: None,
+    });
+    let args_buf = device.create_buffer(&wgpu::BufferDescriptor {
+      size: headers,
+      usage: wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_DST,
+      mapped_at_creation: false,
+      label: None,
+    });
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+      layout: &wf_layout,
+      entries: &[
+        wgpu::BindGroupEntry {
+          binding: 0,
+          resource: path_buf.as_entire_binding(),
+        },
+        wgpu::BindGroupEntry {
+          binding: 1,
+          resource: queue_buf.as_entire_binding(),
+        },
+      ],
+      label: None,
+    });
+    Self {
+      clear: pipeline("wf_clear"),
+      generate: pipeline("wf_generate"),
+      queues: pipeline("wf_queues"),
+      shadow_queue: pipeline("wf_shadow_queue"),
+      extend: pipeline("wf_extend"),
+      shade: [
+        pipeline("wf_shade_glossy"),
+        pipeline("wf_shade_transmission"),
+        pipeline("wf_shade_diffuse"),
+      ],
+      shadow: pipeline("wf_shadow"),
+      bind_group,
+      queue_buf,
+      args_buf,
+    }
+  }
+
+  /// Records `batch` more samples per pixel of a tile of `size`, after
+  /// clearing the ones before with `clear` set. `bind_groups` are those of
+  /// the megakernel. `batch` is up to `max_batch`.
+  pub fn sample(
+    &self,
+    encoder: &mut wgpu::CommandEncoder,
+    bind_groups: &[&wgpu::BindGroup],
+    size: UVec2,
+    batch: u32,
+    clear: bool,
+  ) {
+    let groups = (size + WORKGROUP - 1) / WORKGROUP;
+    if clear {
+      let mut pass = self.begin(encoder, bind_groups);
+      pass.set_pipeline(&self.clear);
+      pass.dispatch_workgroups(groups.x, groups.y, 1);
+    }
+    for _ in 0..batch {
+      let mut pass = self.begin(encoder, bind_groups);
+      pass.set_pipeline(&self.generate);
+      pass.dispatch_workgroups(groups.x, groups.y, 1);
+      drop(pass);
+      for _ in 0..MAX_BOUNCES {
+        self.update_queues(encoder, bind_groups, &self.queues);
+        let mut pass = self.begin(encoder, bind_groups);
+        pass.set_pipeline(&self.extend);
+        pass.dispatch_workgroups_indirect(&self.args_buf, args_offset(0));
+        drop(pass);
+        self.update_queues(encoder, bind_groups, &self.queues);
+        let mut pass = self.begin(encoder, bind_groups);
+        for (i, shade) in self.shade.iter().enumerate() {
+          pass.set_pipeline(shade);
+          pass.dispatch_workgroups_indirect(&self.args_buf, args_offset(i + 1));
+        }
+        drop(pass);
+        self.update_queues(encoder, bind_groups, &self.shadow_queue);
+        let mut pass = self.begin(encoder, bind_groups);
+        pass.set_pipeline(&self.shadow);
+        pass.dispatch_workgroups_indirect(&self.args_buf, args_offset(QUEUES - 1));
+      }
+    }
+  }
+
+  /// Starts a pass with every bind group set.
+  fn begin<'a>(
+    &'a self,
+    encoder: &'a mut wgpu::CommandEncoder,
+    bind_groups: &[&'a wgpu::BindGroup],
+  ) -> wgpu::ComputePass<'a> {
+    let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+    for (i, bind_group) in bind_groups.iter().enumerate() {
+      pass.set_bind_group(i as _, bind_group, &[]);
+    }
+    pass.set_bind_group(bind_groups.len() as _, &self.bind_group, &[]);
+    pass
+  }
+
+  /// Most samples per pixel `sample` takes in one go, within `MAX_ROUNDS`.
+  pub fn max_batch() -> u32 {
+    (MAX_ROUNDS / MAX_BOUNCES).max(1)
+  }
+
+  /// Records handing the pushed paths over to the kernels that consume them
+  /// with `pipeline`, `queues` or `shadow_queue`, with the dispatch arguments
+  /// copied out for the next passes.
+  fn update_queues(
+    &self,
+    encoder: &mut wgpu::CommandEncoder,
+    bind_groups: &[&wgpu::BindGroup],
+    pipeline: &wgpu::ComputePipeline,
+  ) {
+    let mut pass = self.begin(encoder, bind_groups);
+    pass.set_pipeline(pipeline);
+    pass.dispatch_workgroups(1, 1, 1);
+    drop(pass);
+    encoder.copy_buffer_to_buffer(&self.queue_buf, 0, &self.args_buf, 0, self.args_buf.size());
+  }
+}
+
+/// Offset of the indirect dispatch arguments of `queue` in the headers.
+fn args_offset(queue: usize) -> u64 {
+  ((queue * QUEUE_HEADER + 1) * mem::size_of::<u32>()) as _
+}